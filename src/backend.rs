@@ -0,0 +1,181 @@
+//! backend
+//! The operations `Zfs` needs from a pool, executed by the zfs-binary or simulated in tests.
+use crate::zfs::{check, FsType, ZfsError};
+use log::debug;
+use std::collections::HashMap;
+use std::process;
+
+/// Low level access to a pool, executing the decisions of `Zfs`.
+pub trait ZfsBackend {
+    /// Output of `zfs list -Hp -o <columns>`, one tab-separated line per dataset.
+    fn list(&self, columns: &str, fs_type: FsType) -> Result<String, ZfsError>;
+
+    /// Creates the snapshots `names` atomically, with `recursive` also of all descendants.
+    fn snapshot(&self, names: &[&str], recursive: bool) -> Result<(), ZfsError>;
+
+    /// Destroys the snapshot or bookmark `name`.
+    fn destroy(&self, name: &str) -> Result<(), ZfsError>;
+
+    /// Creates the bookmark `bookmark` of the snapshot `snap`.
+    fn bookmark(&self, snap: &str, bookmark: &str) -> Result<(), ZfsError>;
+
+    /// Starts `zfs send`, the stream is readable from its stdout.
+    fn send(&self, source: &[&str]) -> Result<process::Child, ZfsError>;
+
+    /// Estimated size of a `zfs send`-stream in bytes.
+    fn send_size(&self, source: &[&str]) -> Option<u64>;
+
+    /// Values of properties of a dataset, like `written@<snapshot>`, in the given order.
+    fn get(&self, properties: &[&str], dataset: &str) -> Result<Vec<String>, ZfsError>;
+
+    /// Output of `zfs list -Hp -r`, the filesystems, volumes and snapshots of `root` and below.
+    fn list_tree(&self, columns: &str, root: &str) -> Result<String, ZfsError>;
+
+    /// Creates the filesystem `name` and its missing parents.
+    fn create(&self, name: &str) -> Result<(), ZfsError>;
+
+    /// Starts a resumable `zfs receive` into `target`, the stream is written to its stdin.
+    /// With `force` an existing `target` is overwritten.
+    fn receive(&self, target: &str, force: bool) -> Result<process::Child, ZfsError>;
+}
+
+/// Runs the zfs-binary, or a command running it like 'ssh host zfs'.
+pub struct Cli {
+    executable: String,
+    args: Vec<String>,
+}
+
+impl Cli {
+    pub fn new<E: Into<String>>(executable: E) -> Self {
+        Self {
+            executable: executable.into(),
+            args: Vec::new(),
+        }
+    }
+
+    /// Runs the words of `command`, the arguments of the zfs-commands appended.
+    pub fn command(command: &str) -> Self {
+        let mut words = command.split_whitespace().map(String::from);
+        Self {
+            executable: words.next().unwrap_or_default(),
+            args: words.collect(),
+        }
+    }
+
+    fn cmd(&self) -> process::Command {
+        let mut cmd = process::Command::new(&self.executable);
+        cmd.args(&self.args);
+        cmd
+    }
+
+    fn run(&self, args: &[&str]) -> Result<(), ZfsError> {
+        check(self.cmd().args(args).output()?)?;
+        Ok(())
+    }
+}
+
+impl ZfsBackend for Cli {
+    fn list(&self, columns: &str, fs_type: FsType) -> Result<String, ZfsError> {
+        let args = ["list", "-Hp", "-o", columns, "-t", fs_type.as_str()];
+        debug!("zfs {}", args.join(" "));
+        let output = self.cmd().args(args).output()?;
+        if !output.status.success() {
+            // Without the verbose output of the listing.
+            check(process::Output {
+                stdout: Vec::new(),
+                ..output
+            })?;
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn snapshot(&self, names: &[&str], recursive: bool) -> Result<(), ZfsError> {
+        let mut args = vec!["snapshot"];
+        if recursive {
+            args.push("-r");
+        }
+        args.extend(names);
+        self.run(&args)
+    }
+
+    fn destroy(&self, name: &str) -> Result<(), ZfsError> {
+        self.run(&["destroy", name])
+    }
+
+    fn bookmark(&self, snap: &str, bookmark: &str) -> Result<(), ZfsError> {
+        self.run(&["bookmark", snap, bookmark])
+    }
+
+    fn send(&self, source: &[&str]) -> Result<process::Child, ZfsError> {
+        Ok(self
+            .cmd()
+            .arg("send")
+            .args(source)
+            .stdout(process::Stdio::piped())
+            .spawn()?)
+    }
+
+    fn send_size(&self, source: &[&str]) -> Option<u64> {
+        let mut args = vec!["send", "-nP"];
+        args.extend(source);
+        debug!("zfs {}", args.join(" "));
+        let output = self.cmd().args(args).output().ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .rev()
+            .find_map(|l| l.strip_prefix("size\t"))
+            .and_then(|size| size.trim().parse().ok())
+    }
+
+    fn get(&self, properties: &[&str], dataset: &str) -> Result<Vec<String>, ZfsError> {
+        let properties = properties.join(",");
+        let args = ["get", "-Hp", "-o", "property,value", &properties, dataset];
+        debug!("zfs {}", args.join(" "));
+        let output = check(self.cmd().args(args).output()?)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let values: HashMap<&str, &str> =
+            stdout.lines().filter_map(|l| l.split_once('\t')).collect();
+        properties
+            .split(',')
+            .map(|property| match values.get(property) {
+                Some(value) => Ok(value.to_string()),
+                None => Err(ZfsError::InternalError(format!(
+                    "{dataset}: zfs get returned no {property}"
+                ))),
+            })
+            .collect()
+    }
+
+    fn list_tree(&self, columns: &str, root: &str) -> Result<String, ZfsError> {
+        let args = [
+            "list",
+            "-Hp",
+            "-o",
+            columns,
+            "-t",
+            "filesystem,volume,snapshot",
+            "-r",
+            root,
+        ];
+        debug!("zfs {}", args.join(" "));
+        let output = check(self.cmd().args(args).output()?)?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    fn create(&self, name: &str) -> Result<(), ZfsError> {
+        self.run(&["create", "-p", name])
+    }
+
+    fn receive(&self, target: &str, force: bool) -> Result<process::Child, ZfsError> {
+        let mut args = vec!["receive", "-s", "-u"];
+        if force {
+            args.push("-F");
+        }
+        Ok(self
+            .cmd()
+            .args(args)
+            .arg(target)
+            .stdin(process::Stdio::piped())
+            .spawn()?)
+    }
+}
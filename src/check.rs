@@ -0,0 +1,123 @@
+//! check
+//! Monitoring-plugin for Nagios and Icinga, checking that the snapshots of a label are fresh.
+use crate::retention::Age;
+use crate::zfs::{FsType, Zfs};
+use chrono::Duration;
+
+/// States of a monitoring-plugin, their value is the exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum State {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// Checks that every dataset with auto-snapshots of `label` has a snapshot younger than the
+/// thresholds. Returns the state and the line of the plugin.
+pub fn check(zfs: &Zfs, label: &str, warning: Option<Age>, critical: Age) -> (State, String) {
+    let listed = zfs
+        .list_filesystems(FsType::Filesystem)
+        .and_then(|filesystems| Ok((filesystems, zfs.list_filesystems(FsType::Snapshot)?)));
+    let (filesystems, snapshots) = match listed {
+        Ok(listed) => listed,
+        Err(e) => return (State::Unknown, format!("ZFS-SNAPPERS UNKNOWN - {e}")),
+    };
+    let thresholds = format!(
+        "{};{};0",
+        warning.map_or(String::new(), |Age(w)| w.num_seconds().to_string()),
+        critical.0.num_seconds()
+    );
+    let mut state = State::Ok;
+    let mut stale = Vec::new();
+    let mut perfdata = Vec::new();
+    let datasets: Vec<_> = filesystems.iter().filter(|fs| fs.snap_for(label)).collect();
+    for fs in &datasets {
+        let age = zfs
+            .filter_snaps(Some(label), fs, &snapshots)
+            .last()
+            .map(|sn| zfs.now() - sn.date());
+        let dataset_state = match age {
+            None => State::Critical,
+            Some(age) if age > critical.0 => State::Critical,
+            Some(age) if warning.is_some_and(|Age(w)| age > w) => State::Warning,
+            Some(_) => State::Ok,
+        };
+        if dataset_state != State::Ok {
+            let age = age.map_or(String::from("none"), |age| Age(age).to_string());
+            stale.push(format!("{} ({})", fs.name, age));
+        }
+        state = state.max(dataset_state);
+        let value = age.map_or(String::from("U"), |age| {
+            format!("{}s", age.max(Duration::zero()).num_seconds())
+        });
+        perfdata.push(format!("'{}'={value};{thresholds}", fs.name));
+    }
+    let summary = match (state, datasets.len()) {
+        (_, 0) => {
+            state = State::Unknown;
+            format!("no dataset has {label} snapshots enabled")
+        }
+        (State::Ok, n) => format!("{n} datasets have a {label} snapshot"),
+        _ => format!("{label} snapshots too old: {}", stale.join(", ")),
+    };
+    let line = match perfdata.is_empty() {
+        true => format!("ZFS-SNAPPERS {} - {summary}", state.as_str()),
+        false => format!(
+            "ZFS-SNAPPERS {} - {summary} | {}",
+            state.as_str(),
+            perfdata.join(" ")
+        ),
+    };
+    (state, line)
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::fake;
+
+    #[test]
+    fn report_stale_datasets() {
+        let now = fake::now();
+        let pool = fake::www()
+            .filesystem("tank/db", &[("com.sun:auto-snapshot:hourly", "true")])
+            .snapped("tank/www", "hourly", 1, 10)
+            .snapped("tank/db", "hourly", 3, 10);
+        let zfs = Zfs::new(false, "zfs-snappers", vec!["hourly".into()], now).with_backend(pool);
+        let hours = |h| Age(Duration::hours(h));
+        assert_eq!(
+            check(&zfs, "hourly", Some(hours(2)), hours(4)),
+            (
+                State::Warning,
+                String::from(
+                    "ZFS-SNAPPERS WARNING - hourly snapshots too old: tank/db (3h) | \
+                     'tank/db'=10800s;7200;14400;0 'tank/www'=3600s;7200;14400;0"
+                )
+            )
+        );
+        assert_eq!(check(&zfs, "hourly", None, hours(2)).0, State::Critical);
+        assert_eq!(check(&zfs, "hourly", None, hours(4)).0, State::Ok);
+        assert_eq!(
+            check(&zfs, "daily", None, hours(4)),
+            (
+                State::Critical,
+                String::from(
+                    "ZFS-SNAPPERS CRITICAL - daily snapshots too old: tank/www (none) | \
+                     'tank/www'=U;;14400;0"
+                )
+            )
+        );
+    }
+}
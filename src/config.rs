@@ -0,0 +1,497 @@
+//! config
+//! Reading and validating the configuration-file.
+use crate::hooks::Hooks;
+use crate::lock::LockConfig;
+use crate::metrics::MetricsConfig;
+use crate::name::{Scheme, Template};
+use crate::retention::{Age, Gfs, Policy, Size};
+use crate::schedule::Schedule;
+use crate::zone::Zone;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Location of the configuration-file, if none is given on the command line.
+pub const DEFAULT_PATH: &str = "/etc/zfs-snappers.toml";
+
+/// Settings which can be overridden per label, per dataset or on the command line.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Overrides {
+    pub keep: Option<usize>,
+    pub min_size: Option<Size>,
+    pub keep_within: Option<Age>,
+    pub max_age: Option<Age>,
+}
+
+impl Overrides {
+    fn apply(&self, policy: &mut Policy) {
+        if let Some(keep) = self.keep {
+            policy.keep = keep;
+        }
+        if let Some(min_size) = self.min_size {
+            policy.min_size = min_size;
+        }
+        if self.keep_within.is_some() {
+            policy.keep_within = self.keep_within;
+        }
+        if self.max_age.is_some() {
+            policy.max_age = self.max_age;
+        }
+    }
+}
+
+/// Settings of a label.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct LabelConfig {
+    pub keep: Option<usize>,
+    pub min_size: Option<Size>,
+    pub keep_within: Option<Age>,
+    pub max_age: Option<Age>,
+    /// When the daemon creates snapshots of this label, a keyword like 'hourly' or a
+    /// cron-expression. Defaults to the label-name if that is a keyword.
+    pub schedule: Option<String>,
+}
+
+impl LabelConfig {
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            keep: self.keep,
+            min_size: self.min_size,
+            keep_within: self.keep_within,
+            max_age: self.max_age,
+        }
+    }
+}
+
+/// Overrides for a single dataset, optionally refined per label.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DatasetConfig {
+    pub keep: Option<usize>,
+    pub min_size: Option<Size>,
+    pub keep_within: Option<Age>,
+    pub max_age: Option<Age>,
+    pub gfs: Option<Gfs>,
+    pub hooks: Option<Hooks>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, Overrides>,
+}
+
+impl DatasetConfig {
+    fn overrides(&self) -> Overrides {
+        Overrides {
+            keep: self.keep,
+            min_size: self.min_size,
+            keep_within: self.keep_within,
+            max_age: self.max_age,
+        }
+    }
+}
+
+/// Datasets whose snapshots are created atomically by a single `zfs snapshot`.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct GroupConfig {
+    pub datasets: Vec<String>,
+    /// Run once around the snapshot of the whole group.
+    pub hooks: Option<Hooks>,
+}
+
+/// Where `replicate` sends the snapshots to.
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Dataset receiving the replicas, 'backup' receives 'tank/www' as 'backup/tank/www'.
+    pub target: String,
+    /// zfs-command of the receiving side, e.g. 'ssh backup-host zfs'. Defaults to the local zfs.
+    pub command: Option<String>,
+}
+
+/// Content of the configuration-file.
+///
+/// ```toml
+/// prefix = "zfs-snappers"
+/// template = "{prefix}_{label}-%Y-%m-%d-%H%M"
+/// timezone = "UTC"
+/// adopt = ["zfs-auto-snapshot", "sanoid"]
+/// keep = 8
+///
+/// [labels.hourly]
+/// keep = 24
+/// keep_within = "48h"
+///
+/// [labels.quarter]
+/// schedule = "*/15 * * * *"
+///
+/// [datasets."tank/db"]
+/// min_size = "1M"
+/// max_age = "90d"
+///
+/// [datasets."tank/db".labels.hourly]
+/// keep = 48
+///
+/// [datasets."tank/www".gfs]
+/// hourly = 24
+/// daily = 14
+/// weekly = 8
+/// monthly = 12
+///
+/// [groups.db]
+/// datasets = ["tank/db/data", "tank/db/wal"]
+///
+/// [groups.db.hooks]
+/// pre_snapshot = "psql -c CHECKPOINT"
+/// timeout = "30s"
+///
+/// [replication]
+/// target = "backup"
+/// command = "ssh backup-host zfs"
+///
+/// [metrics]
+/// path = "/var/lib/node_exporter/textfile_collector/zfs-snappers.prom"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+pub struct Config {
+    pub prefix: String,
+    /// Names of new snapshots, also recognising the existing ones.
+    pub template: Template,
+    /// Time zone of the names, of the `gfs` buckets and of the schedules.
+    pub timezone: Zone,
+    /// Naming schemes of other tools, whose snapshots are counted and pruned like the own ones.
+    pub adopt: Vec<Scheme>,
+    pub keep: usize,
+    pub min_size: Size,
+    pub keep_within: Option<Age>,
+    pub max_age: Option<Age>,
+    /// Thins out the snapshots of all labels by grandfather-father-son buckets.
+    pub gfs: Option<Gfs>,
+    pub labels: BTreeMap<String, LabelConfig>,
+    pub datasets: BTreeMap<String, DatasetConfig>,
+    /// Consistency groups, snapped together whenever one of their datasets is due.
+    pub groups: BTreeMap<String, GroupConfig>,
+    /// Hooks of all datasets, for each hook a dataset does not set itself.
+    pub hooks: Option<Hooks>,
+    /// Keeps several instances from pruning the same pools.
+    pub lock: LockConfig,
+    pub replication: Option<ReplicationConfig>,
+    /// Prometheus metrics written after every run.
+    pub metrics: Option<MetricsConfig>,
+    /// Given on the command line, wins over everything read from the file.
+    #[serde(skip)]
+    pub cli: Overrides,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            prefix: String::from("zfs-snappers"),
+            template: Template::default(),
+            timezone: Zone::Utc,
+            adopt: Vec::new(),
+            keep: 8,
+            min_size: Size(0),
+            keep_within: None,
+            max_age: None,
+            gfs: None,
+            labels: BTreeMap::new(),
+            datasets: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            hooks: None,
+            lock: LockConfig::default(),
+            replication: None,
+            metrics: None,
+            cli: Overrides::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads and validates the configuration-file, `DEFAULT_PATH` or the defaults if `None`.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let content = match path {
+            Some(path) => fs::read_to_string(path)?,
+            None => match fs::read_to_string(DEFAULT_PATH) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+                Err(e) => return Err(e.into()),
+            },
+        };
+        Self::parse(&content)
+    }
+
+    /// Parses and validates the content of a configuration-file.
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        check_name("prefix", &self.prefix)?;
+        // Without the zone the same local time repeats when daylight saving ends.
+        if self.timezone != Zone::Utc && !self.template.has_zone() {
+            return Err(ConfigError::Invalid(format!(
+                "timezone '{}' needs {{zone}} in template '{}'",
+                self.timezone, self.template
+            )));
+        }
+        for (label, lc) in &self.labels {
+            check_name("label", label)?;
+            if let Some(schedule) = &lc.schedule {
+                schedule.parse::<Schedule>().map_err(ConfigError::Invalid)?;
+            }
+        }
+        for (dataset, ds) in &self.datasets {
+            check_dataset(dataset)?;
+            for label in ds.labels.keys() {
+                check_name("label", label)?;
+            }
+        }
+        let mut grouped = BTreeMap::new();
+        for (group, gc) in &self.groups {
+            check_name("group", group)?;
+            if gc.datasets.is_empty() {
+                return Err(ConfigError::Invalid(format!(
+                    "group '{group}' has no datasets"
+                )));
+            }
+            // A single `zfs snapshot` can not span pools.
+            let pool = |dataset: &str| dataset.split('/').next().map(String::from);
+            if gc.datasets.iter().any(|d| pool(d) != pool(&gc.datasets[0])) {
+                return Err(ConfigError::Invalid(format!(
+                    "group '{group}' spans several pools"
+                )));
+            }
+            for dataset in &gc.datasets {
+                check_dataset(dataset)?;
+                if let Some(other) = grouped.insert(dataset, group) {
+                    return Err(ConfigError::Invalid(format!(
+                        "'{dataset}' is part of the groups '{other}' and '{group}'"
+                    )));
+                }
+            }
+        }
+        if let Some(replication) = &self.replication {
+            check_dataset(&replication.target)?;
+            if let Some(command) = &replication.command {
+                if command.trim().is_empty() {
+                    return Err(ConfigError::Invalid("replication command is empty".into()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the settings for a dataset and label. More specific settings win:
+    /// defaults < label < dataset < dataset-label < command line.
+    pub fn policy(&self, label: &str, dataset: &str) -> Policy {
+        let mut policy = Policy {
+            keep: self.keep,
+            min_size: self.min_size,
+            keep_within: self.keep_within,
+            max_age: self.max_age,
+            gfs: self.gfs,
+            zone: self.timezone,
+        };
+        if let Some(lc) = self.labels.get(label) {
+            lc.overrides().apply(&mut policy);
+        }
+        if let Some(ds) = self.datasets.get(dataset) {
+            ds.overrides().apply(&mut policy);
+            if ds.gfs.is_some() {
+                policy.gfs = ds.gfs;
+            }
+            if let Some(o) = ds.labels.get(label) {
+                o.apply(&mut policy);
+            }
+        }
+        self.cli.apply(&mut policy);
+        policy
+    }
+
+    /// The hooks of a dataset, the global ones for each hook it does not set itself.
+    pub fn hooks(&self, dataset: &str) -> Option<Hooks> {
+        let own = self.datasets.get(dataset).and_then(|ds| ds.hooks.as_ref());
+        match (own, &self.hooks) {
+            (Some(own), Some(global)) => Some(own.or(global)),
+            (own, global) => own.or(global.as_ref()).cloned(),
+        }
+    }
+
+    /// The consistency group of a dataset, if it is part of one.
+    pub fn group(&self, dataset: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(_, gc)| gc.datasets.iter().any(|d| d == dataset))
+            .map(|(group, _)| group.as_str())
+    }
+
+    /// The schedule of a label, if it has one.
+    pub fn schedule(&self, label: &str) -> Option<Schedule> {
+        let schedule = match self.labels.get(label).and_then(|lc| lc.schedule.as_ref()) {
+            Some(schedule) => schedule.parse().ok(),
+            None => Schedule::for_label(label),
+        };
+        schedule.map(|schedule| schedule.in_zone(self.timezone))
+    }
+}
+
+/// Checks that `value` may be used as part of a snapshot-name.
+pub fn check_name(what: &str, value: &str) -> Result<(), ConfigError> {
+    let valid = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    match valid {
+        true => Ok(()),
+        false => Err(ConfigError::Invalid(format!(
+            "{what} '{value}' may only contain alphanumeric characters, '-', '_', '.' or ':'"
+        ))),
+    }
+}
+
+/// Checks that `value` is the name of a filesystem or volume.
+pub fn check_dataset(value: &str) -> Result<(), ConfigError> {
+    match value.is_empty() || value.contains(['@', '#']) || value.ends_with('/') {
+        true => Err(ConfigError::Invalid(format!(
+            "'{value}' is not a valid dataset name"
+        ))),
+        false => Ok(()),
+    }
+}
+
+pub enum ConfigError {
+    IOError(std::io::Error),
+    ParseError(toml::de::Error),
+    Invalid(String),
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(e: std::io::Error) -> Self {
+        ConfigError::IOError(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::ParseError(e)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::IOError(e) => write!(f, "config: {}", e),
+            ConfigError::ParseError(e) => write!(f, "config: {}", e),
+            ConfigError::Invalid(s) => write!(f, "config: {}", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn resolve_policy_by_specificity() {
+        let mut config = Config::parse(
+            r#"
+            keep = 5
+            min_size = 10
+
+            [labels.hourly]
+            keep = 24
+
+            [datasets."tank/db"]
+            min_size = "1M"
+            max_age = "90d"
+
+            [datasets."tank/db".labels.hourly]
+            keep = 48
+            "#,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(
+            config.policy("daily", "tank/www"),
+            Policy {
+                keep: 5,
+                min_size: Size(10 * 1024),
+                keep_within: None,
+                max_age: None,
+                gfs: None,
+                zone: Zone::Utc,
+            }
+        );
+        assert_eq!(config.policy("hourly", "tank/www").keep, 24);
+        assert_eq!(
+            config.policy("hourly", "tank/db"),
+            Policy {
+                keep: 48,
+                min_size: "1M".parse().unwrap(),
+                keep_within: None,
+                max_age: "90d".parse().ok(),
+                gfs: None,
+                zone: Zone::Utc,
+            }
+        );
+        assert_eq!(config.policy("daily", "tank/db").keep, 5);
+        config.cli.keep = Some(2);
+        assert_eq!(config.policy("hourly", "tank/db").keep, 2);
+        config.gfs = Some(Gfs {
+            daily: 7,
+            ..Gfs::default()
+        });
+        assert_eq!(config.policy("hourly", "tank/db").gfs.unwrap().daily, 7);
+    }
+
+    #[test]
+    fn take_hooks_not_set_by_the_dataset_from_the_global_ones() {
+        let config = Config::parse(
+            "[hooks]\npre_snapshot = \"sync\"\npre_destroy = \"true\"\n\
+             [datasets.\"tank/db\".hooks]\npre_snapshot = \"psql -c CHECKPOINT\"",
+        )
+        .ok()
+        .unwrap();
+        let hooks = config.hooks("tank/db").unwrap();
+        assert_eq!(hooks.pre_snapshot.as_deref(), Some("psql -c CHECKPOINT"));
+        assert_eq!(hooks.pre_destroy.as_deref(), Some("true"));
+        assert_eq!(
+            config.hooks("tank/www").unwrap().pre_snapshot.as_deref(),
+            Some("sync")
+        );
+        assert_eq!(Config::default().hooks("tank/db"), None);
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        assert!(Config::parse("prefix = \"snap@me\"").is_err());
+        assert!(Config::parse("[labels.\"hour ly\"]").is_err());
+        assert!(Config::parse("[datasets.\"tank@x\"]").is_err());
+        assert!(Config::parse("[replication]\ntarget = \"backup/\"").is_err());
+        assert!(Config::parse("kepp = 3").is_err());
+        assert!(Config::parse("max_age = \"90\"").is_err());
+        assert!(Config::parse("min_size = \"10 parsecs\"").is_err());
+        assert!(Config::parse("[labels.hourly]\nschedule = \"often\"").is_err());
+        assert!(Config::parse("[groups.db]\ndatasets = []").is_err());
+        assert!(Config::parse("[datasets.tank.hooks]\non_pre_failure = \"ignore\"").is_err());
+        assert!(Config::parse(
+            "[groups.a]\ndatasets = [\"tank/db\"]\n[groups.b]\ndatasets = [\"tank/db\"]"
+        )
+        .is_err());
+        assert!(Config::parse("[groups.db]\ndatasets = [\"tank/db\", \"backup/db\"]").is_err());
+        assert!(Config::parse("timezone = \"Mars/Olympus_Mons\"").is_err());
+        assert!(Config::parse("adopt = [\"snapper\"]").is_err());
+        assert!(Config::parse("timezone = \"Europe/Berlin\"").is_err());
+        assert!(Config::parse(
+            "timezone = \"Europe/Berlin\"\ntemplate = \"{prefix}_{label}-%F-%H%M-{zone}\""
+        )
+        .is_ok());
+        assert_eq!(Config::parse("").ok(), Some(Config::default()));
+    }
+}
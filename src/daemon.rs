@@ -0,0 +1,118 @@
+//! daemon
+//! Keeps running and creates the snapshots of every label when they are due.
+use crate::config::{self, Config, ConfigError};
+use crate::schedule::Schedule;
+use crate::zfs::{FsType, Zfs};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Longest time to sleep without looking for signals and due labels.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Seconds to retry a label whose run failed.
+const RETRY_SECONDS: i64 = 60;
+
+/// Schedules `labels` (all configured if empty) until SIGTERM or SIGINT, SIGHUP reloads
+/// the configuration through `load`.
+pub fn run<F>(labels: &[String], dry_run: bool, load: F) -> Result<(), String>
+where
+    F: Fn() -> Result<Config, ConfigError>,
+{
+    let term = Arc::new(AtomicBool::new(false));
+    let hup = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&term)).map_err(|e| e.to_string())?;
+    }
+    signal_hook::flag::register(SIGHUP, Arc::clone(&hup)).map_err(|e| e.to_string())?;
+
+    let mut config = load().map_err(|e| e.to_string())?;
+    let mut schedules = resolve_schedules(&config, labels)?;
+    // Catch up on runs missed while not running, by the dataset which missed the most.
+    let scheduled = schedules.keys().cloned().collect();
+    let zfs = Zfs::from_config(&config, dry_run, scheduled, Utc::now());
+    let listed = zfs
+        .list_filesystems(FsType::Filesystem)
+        .and_then(|filesystems| Ok((filesystems, zfs.list_filesystems(FsType::Snapshot)?)));
+    let (filesystems, snaps) = listed.map_err(|e| e.to_string())?;
+    let mut last_run: BTreeMap<String, DateTime<Utc>> = schedules
+        .keys()
+        .filter_map(|label| {
+            let last = zfs.last_snapshot(label, &filesystems, &snaps)?;
+            Some((label.clone(), last))
+        })
+        .collect();
+    let mut retry_at: BTreeMap<String, DateTime<Utc>> = BTreeMap::new();
+    info!("scheduling {:?}", schedules.keys().collect::<Vec<_>>());
+
+    while !term.load(Ordering::Relaxed) {
+        if hup.swap(false, Ordering::Relaxed) {
+            match load()
+                .map_err(|e| e.to_string())
+                .and_then(|c| resolve_schedules(&c, labels).map(|s| (c, s)))
+            {
+                Ok((c, s)) => {
+                    info!("configuration reloaded");
+                    config = c;
+                    schedules = s;
+                }
+                Err(e) => error!("{}, keeping the previous configuration", e),
+            }
+        }
+        let now = Utc::now();
+        let due: Vec<String> = schedules
+            .iter()
+            .filter(|(label, schedule)| schedule.is_due(last_run.get(*label).copied(), now))
+            .filter(|(label, _)| retry_at.get(*label).is_none_or(|at| *at <= now))
+            .map(|(label, _)| label.clone())
+            .collect();
+        if due.is_empty() {
+            thread::sleep(TICK);
+            continue;
+        }
+        info!("due: {}", due.join(", "));
+        let zfs = Zfs::from_config(&config, dry_run, due.clone(), now);
+        let report = crate::run(&zfs, &config);
+        for label in due {
+            if !report.done(&label) {
+                error!("{} failed, retrying in {}s", label, RETRY_SECONDS);
+                retry_at.insert(label, now + chrono::Duration::seconds(RETRY_SECONDS));
+                continue;
+            }
+            debug!("next {} at {:?}", label, schedules[&label].next(now));
+            retry_at.remove(&label);
+            last_run.insert(label, now);
+        }
+    }
+    info!("terminating");
+    Ok(())
+}
+
+/// Schedules of the given labels, or of all configured labels.
+fn resolve_schedules(
+    config: &Config,
+    labels: &[String],
+) -> Result<BTreeMap<String, Schedule>, String> {
+    let labels: Vec<&String> = match labels.is_empty() {
+        true => config.labels.keys().collect(),
+        false => labels.iter().collect(),
+    };
+    if labels.is_empty() {
+        return Err(String::from("no labels given and none configured"));
+    }
+    labels
+        .into_iter()
+        .map(|label| {
+            config::check_name("label", label).map_err(|e| e.to_string())?;
+            config
+                .schedule(label)
+                .map(|s| (label.clone(), s))
+                .ok_or_else(|| format!("label '{label}' has no schedule"))
+        })
+        .collect()
+}
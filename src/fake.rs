@@ -0,0 +1,407 @@
+//! fake
+//! An in-memory pool for testing the decisions of `Zfs` without the zfs-binary.
+use crate::backend::ZfsBackend;
+use crate::zfs::{FsType, ZfsError};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, fs};
+
+/// Number of the last directory made by `lock_dir`.
+static LOCK_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A new directory for the lock files of a test, which would otherwise wait for the locks of
+/// the other tests and of other instances.
+pub fn lock_dir() -> PathBuf {
+    let n = LOCK_DIRS.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("zfs-snappers-locks-{}/{n}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The time of the fixtures.
+pub fn now() -> DateTime<Utc> {
+    Utc.ymd(2022, 7, 12).and_hms(12, 0, 0)
+}
+
+/// A pool at `now()` with `tank` and `tank/www`, which has snapshots enabled and 4 KiB written.
+pub fn www() -> FakePool {
+    FakePool::new(now()).filesystem("tank", &[]).filesystem(
+        "tank/www",
+        &[("com.sun:auto-snapshot", "true"), ("written", "4096")],
+    )
+}
+
+#[derive(Debug, Clone)]
+struct Dataset {
+    creation: i64,
+    guid: u64,
+    properties: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct Pool {
+    datasets: BTreeMap<String, Dataset>,
+    now: i64,
+    next_guid: u64,
+    calls: Vec<String>,
+    /// Number of `zfs get` calls.
+    gets: usize,
+    holds: HashSet<String>,
+    /// Filesystems whose snapshots are denied.
+    denied: HashSet<String>,
+    volumes: HashSet<String>,
+}
+
+impl Pool {
+    fn add(&mut self, name: &str, creation: i64, properties: &[(&str, &str)]) {
+        self.next_guid += 1;
+        let dataset = Dataset {
+            creation,
+            guid: self.next_guid,
+            properties: properties
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        self.datasets.insert(name.to_owned(), dataset);
+    }
+
+    fn fs_type(&self, name: &str) -> FsType {
+        match (name.contains('@'), name.contains('#')) {
+            (true, _) => FsType::Snapshot,
+            (_, true) => FsType::Bookmark,
+            _ if self.volumes.contains(name) => FsType::Volume,
+            _ => FsType::Filesystem,
+        }
+    }
+}
+
+/// A simulated pool. Clones share the same pool, so a test can inspect it after handing a
+/// clone to `Zfs`.
+#[derive(Debug, Default, Clone)]
+pub struct FakePool(Rc<RefCell<Pool>>);
+
+impl FakePool {
+    /// An empty pool, new snapshots are created at `now`.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        let pool = FakePool::default();
+        pool.0.borrow_mut().now = now.timestamp();
+        pool
+    }
+
+    /// Adds a filesystem with properties like `("com.sun:auto-snapshot", "true")`.
+    pub fn filesystem(self, name: &str, properties: &[(&str, &str)]) -> Self {
+        let now = self.0.borrow().now;
+        self.0.borrow_mut().add(name, now, properties);
+        self
+    }
+
+    /// Adds a snapshot created at `date`, with `written` bytes since its predecessor. These
+    /// bytes are also its `used` ones, as if nothing was overwritten since.
+    pub fn snapshot(self, name: &str, date: DateTime<Utc>, written: u64) -> Self {
+        let written = written.to_string();
+        let properties = [("written", written.as_str()), ("used", &written)];
+        self.0.borrow_mut().add(name, date.timestamp(), &properties);
+        self
+    }
+
+    /// Adds a snapshot of `label` created `hours` before `now`, named by the default template
+    /// like `tank/www@zfs-snappers_hourly-2022-07-12-1100`.
+    pub fn snapped(self, dataset: &str, label: &str, hours: i64, written: u64) -> Self {
+        let date = Utc.timestamp(self.0.borrow().now, 0) - Duration::hours(hours);
+        let name = format!(
+            "{dataset}@zfs-snappers_{label}-{}",
+            date.format("%Y-%m-%d-%H%M")
+        );
+        self.snapshot(&name, date, written)
+    }
+
+    /// Adds a volume.
+    pub fn volume(self, name: &str) -> Self {
+        let now = self.0.borrow().now;
+        self.0.borrow_mut().add(name, now, &[]);
+        self.0.borrow_mut().volumes.insert(name.to_owned());
+        self
+    }
+
+    /// Holds a snapshot like another tool would, destroying it fails as busy.
+    pub fn hold(self, name: &str) -> Self {
+        self.0.borrow_mut().holds.insert(name.to_owned());
+        self
+    }
+
+    /// Denies snapshots of a filesystem, as if the permission were missing.
+    pub fn deny(self, name: &str) -> Self {
+        self.0.borrow_mut().denied.insert(name.to_owned());
+        self
+    }
+
+    /// Names of the datasets of a type, sorted.
+    pub fn names(&self, fs_type: FsType) -> Vec<String> {
+        let pool = self.0.borrow();
+        pool.datasets
+            .keys()
+            .filter(|name| pool.fs_type(name) == fs_type)
+            .cloned()
+            .collect()
+    }
+
+    /// The changing zfs-commands executed so far, like `snapshot -r tank@x`.
+    pub fn calls(&self) -> Vec<String> {
+        self.0.borrow().calls.clone()
+    }
+
+    /// Number of `zfs get` calls so far.
+    pub fn gets(&self) -> usize {
+        self.0.borrow().gets
+    }
+
+    fn call(&self, args: &[&str]) {
+        self.0.borrow_mut().calls.push(args.join(" "));
+    }
+
+    /// `written@<snapshot>` sums up the bytes written to the snapshots after it and to the
+    /// dataset, other properties are read as they are.
+    fn property(&self, property: &str, dataset: &str) -> Result<String, ZfsError> {
+        let pool = self.0.borrow();
+        let missing =
+            || ZfsError::NoSuchDataset(format!("cannot open '{dataset}': dataset does not exist"));
+        let ds = pool.datasets.get(dataset).ok_or_else(missing)?;
+        let written = |ds: &Dataset| -> u64 {
+            ds.properties
+                .get("written")
+                .and_then(|w| w.parse().ok())
+                .unwrap_or_default()
+        };
+        match property.strip_prefix("written@") {
+            Some(snap) => {
+                let since = pool
+                    .datasets
+                    .get(&format!("{dataset}@{snap}"))
+                    .ok_or_else(missing)?;
+                let later: u64 = pool
+                    .datasets
+                    .iter()
+                    .filter(|(name, sn)| {
+                        name.split_once('@').is_some_and(|(fs, _)| fs == dataset)
+                            && sn.creation > since.creation
+                    })
+                    .map(|(_, sn)| written(sn))
+                    .sum();
+                Ok((later + written(ds)).to_string())
+            }
+            None => Ok(ds.properties.get(property).cloned().unwrap_or("-".into())),
+        }
+    }
+
+    fn check_create(&self, name: &str, parent: &str) -> Result<(), ZfsError> {
+        let pool = self.0.borrow();
+        if !pool.datasets.contains_key(parent) {
+            return Err(ZfsError::NoSuchDataset(format!(
+                "cannot open '{parent}': dataset does not exist"
+            )));
+        }
+        if pool.denied.contains(parent) {
+            return Err(ZfsError::PermissionDenied(format!(
+                "cannot create snapshots : permission denied for '{name}'"
+            )));
+        }
+        if pool.datasets.contains_key(name) {
+            return Err(ZfsError::Exists(format!(
+                "cannot create '{name}': dataset already exists"
+            )));
+        }
+        Ok(())
+    }
+
+    fn create(&self, name: &str, parent: &str) -> Result<(), ZfsError> {
+        self.check_create(name, parent)?;
+        let mut pool = self.0.borrow_mut();
+        // The data written to a filesystem so far belongs to its new snapshot.
+        let written = match pool.fs_type(parent) {
+            FsType::Filesystem | FsType::Volume => pool
+                .datasets
+                .get_mut(parent)
+                .unwrap()
+                .properties
+                .insert("written".into(), "0".into()),
+            _ => None,
+        }
+        .unwrap_or("0".into());
+        let now = pool.now;
+        pool.add(name, now, &[("written", &written), ("used", &written)]);
+        Ok(())
+    }
+}
+
+impl ZfsBackend for FakePool {
+    fn list(&self, columns: &str, fs_type: FsType) -> Result<String, ZfsError> {
+        let pool = self.0.borrow();
+        let lines: Vec<String> = pool
+            .datasets
+            .iter()
+            .filter(|(name, _)| pool.fs_type(name) == fs_type)
+            .map(|(name, ds)| {
+                columns
+                    .split(',')
+                    .map(|column| match column {
+                        "name" => name.clone(),
+                        "creation" => ds.creation.to_string(),
+                        "guid" => ds.guid.to_string(),
+                        _ => ds.properties.get(column).cloned().unwrap_or("-".into()),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\t")
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    fn snapshot(&self, names: &[&str], recursive: bool) -> Result<(), ZfsError> {
+        let mut args = vec!["snapshot"];
+        if recursive {
+            args.push("-r");
+        }
+        args.extend(names);
+        self.call(&args);
+        let mut snaps: Vec<(String, String)> = Vec::new();
+        for name in names {
+            let (fs, snap) = name.split_once('@').unwrap_or((name, ""));
+            snaps.push((name.to_string(), fs.to_owned()));
+            if recursive {
+                let children = format!("{fs}/");
+                let volumes = self.names(FsType::Volume);
+                for child in self.names(FsType::Filesystem).into_iter().chain(volumes) {
+                    if child.starts_with(&children) {
+                        snaps.push((format!("{child}@{snap}"), child));
+                    }
+                }
+            }
+        }
+        // All or nothing.
+        for (name, fs) in &snaps {
+            self.check_create(name, fs)?;
+        }
+        for (name, fs) in &snaps {
+            self.create(name, fs)?;
+        }
+        Ok(())
+    }
+
+    fn destroy(&self, name: &str) -> Result<(), ZfsError> {
+        self.call(&["destroy", name]);
+        // Expands the comma-list of 'fs@a,b,c', all or nothing is destroyed.
+        let names: Vec<String> = match name.split_once('@') {
+            Some((fs, snaps)) => snaps.split(',').map(|s| format!("{fs}@{s}")).collect(),
+            None => vec![name.to_owned()],
+        };
+        let mut pool = self.0.borrow_mut();
+        for name in &names {
+            if pool.holds.contains(name) {
+                return Err(ZfsError::Busy(format!(
+                    "cannot destroy snapshot {name}: dataset is busy"
+                )));
+            }
+            if !pool.datasets.contains_key(name) {
+                return Err(ZfsError::NoSuchDataset(format!(
+                    "could not find any snapshots to destroy; check snapshot names: {name}"
+                )));
+            }
+        }
+        for name in &names {
+            pool.datasets.remove(name);
+        }
+        Ok(())
+    }
+
+    fn bookmark(&self, snap: &str, bookmark: &str) -> Result<(), ZfsError> {
+        self.call(&["bookmark", snap, bookmark]);
+        self.create(bookmark, snap)?;
+        let mut pool = self.0.borrow_mut();
+        let (creation, guid) = (pool.datasets[snap].creation, pool.datasets[snap].guid);
+        let bm = pool.datasets.get_mut(bookmark).unwrap();
+        bm.creation = creation;
+        bm.guid = guid;
+        Ok(())
+    }
+
+    /// The stream is the arguments, like 'tank@a' or '-i tank@a tank@b'.
+    fn send(&self, source: &[&str]) -> Result<process::Child, ZfsError> {
+        self.call(&[&["send"], source].concat());
+        Ok(process::Command::new("sh")
+            .args(["-c", "printf '%s' \"$*\"", "send"])
+            .args(source)
+            .stdout(process::Stdio::piped())
+            .spawn()?)
+    }
+
+    fn send_size(&self, _source: &[&str]) -> Option<u64> {
+        None
+    }
+
+    fn get(&self, properties: &[&str], dataset: &str) -> Result<Vec<String>, ZfsError> {
+        self.0.borrow_mut().gets += 1;
+        properties
+            .iter()
+            .map(|property| self.property(property, dataset))
+            .collect()
+    }
+
+    fn list_tree(&self, columns: &str, root: &str) -> Result<String, ZfsError> {
+        let below = |name: &str| {
+            name == root
+                || [format!("{root}/"), format!("{root}@")]
+                    .iter()
+                    .any(|p| name.starts_with(p))
+        };
+        if !self.0.borrow().datasets.contains_key(root) {
+            return Err(ZfsError::NoSuchDataset(format!(
+                "cannot open '{root}': dataset does not exist"
+            )));
+        }
+        let mut lines = Vec::new();
+        for fs_type in [FsType::Filesystem, FsType::Volume, FsType::Snapshot] {
+            let listed = self.list(columns, fs_type)?;
+            lines.extend(
+                listed
+                    .lines()
+                    .filter(|l| below(l.split('\t').next().unwrap_or_default()))
+                    .map(String::from),
+            );
+        }
+        lines.sort();
+        Ok(lines.join("\n"))
+    }
+
+    fn create(&self, name: &str) -> Result<(), ZfsError> {
+        self.call(&["create", "-p", name]);
+        let mut pool = self.0.borrow_mut();
+        let now = pool.now;
+        let mut parent = String::new();
+        for part in name.split('/') {
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(part);
+            if !pool.datasets.contains_key(&parent) {
+                pool.add(&parent, now, &[]);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive(&self, target: &str, force: bool) -> Result<process::Child, ZfsError> {
+        match force {
+            true => self.call(&["receive", "-F", target]),
+            false => self.call(&["receive", target]),
+        }
+        Err(ZfsError::InternalError(
+            "receiving is not simulated by the fake pool".into(),
+        ))
+    }
+}
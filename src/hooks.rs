@@ -0,0 +1,338 @@
+//! hooks
+//! Commands run around creating snapshots, e.g. to quiesce an application, and around
+//! destroying them.
+use crate::retention::Age;
+use log::{debug, info};
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a hook may take, if none is configured.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval to look whether a hook has finished.
+const POLL: Duration = Duration::from_millis(20);
+
+/// What a failing pre-hook means for the snapshot.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// No snapshot is created.
+    #[default]
+    Abort,
+    /// The snapshot is created anyway.
+    Continue,
+}
+
+/// Hooks of a dataset or consistency group. The commands are run by `sh -c`.
+///
+/// ```toml
+/// [datasets."tank/db".hooks]
+/// pre_snapshot = "psql -c CHECKPOINT"
+/// post_snapshot = "/usr/local/bin/resume-db"
+/// timeout = "30s"
+/// on_pre_failure = "continue"
+/// pre_destroy = "! backup-tool is-copying \"$ZFS_SNAPPERS_SNAPSHOT\""
+/// post_prune = "logger pruned $ZFS_SNAPPERS_DESTROYED"
+/// ```
+#[derive(Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Run right before the snapshot.
+    pub pre_snapshot: Option<String>,
+    /// Run right after the snapshot, also if it failed, to resume what `pre_snapshot` stopped.
+    pub post_snapshot: Option<String>,
+    /// Hooks running longer are killed and count as failed, 60s by default.
+    pub timeout: Option<Age>,
+    /// Whether a failing `pre_snapshot` aborts the snapshot, it does by default.
+    pub on_pre_failure: Option<OnFailure>,
+    /// Run before destroying a snapshot, a non-zero exit status keeps it.
+    pub pre_destroy: Option<String>,
+    /// Run after pruning a dataset.
+    pub post_prune: Option<String>,
+}
+
+impl Hooks {
+    /// These hooks, taking each one not set from `fallback`.
+    pub fn or(&self, fallback: &Hooks) -> Hooks {
+        Hooks {
+            pre_snapshot: self
+                .pre_snapshot
+                .clone()
+                .or_else(|| fallback.pre_snapshot.clone()),
+            post_snapshot: self
+                .post_snapshot
+                .clone()
+                .or_else(|| fallback.post_snapshot.clone()),
+            timeout: self.timeout.or(fallback.timeout),
+            on_pre_failure: self.on_pre_failure.or(fallback.on_pre_failure),
+            pre_destroy: self
+                .pre_destroy
+                .clone()
+                .or_else(|| fallback.pre_destroy.clone()),
+            post_prune: self
+                .post_prune
+                .clone()
+                .or_else(|| fallback.post_prune.clone()),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+            .and_then(|Age(timeout)| timeout.to_std().ok())
+            .unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Asks the pre-destroy hook, if any, whether `snapshot` may be destroyed. The hook sees
+    /// `ZFS_SNAPPERS_DATASET` and `ZFS_SNAPPERS_SNAPSHOT`.
+    pub fn may_destroy(
+        &self,
+        dataset: &str,
+        snapshot: &str,
+        pretend: bool,
+    ) -> Result<bool, HookError> {
+        let command = match &self.pre_destroy {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        info!("pre_destroy hook: {}", command);
+        if pretend {
+            return Ok(true);
+        }
+        let env = [
+            ("ZFS_SNAPPERS_HOOK", String::from("pre_destroy")),
+            ("ZFS_SNAPPERS_DATASET", dataset.into()),
+            ("ZFS_SNAPPERS_SNAPSHOT", snapshot.into()),
+        ];
+        match execute(command, &env, self.timeout()) {
+            Ok(()) => Ok(true),
+            Err(HookError::Failed(_, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs the post-prune hook, if any. The hook sees `ZFS_SNAPPERS_DATASET` and the
+    /// space-separated `ZFS_SNAPPERS_DESTROYED` snapshots.
+    pub fn post_prune(
+        &self,
+        dataset: &str,
+        destroyed: &[&str],
+        pretend: bool,
+    ) -> Result<(), HookError> {
+        let command = match &self.post_prune {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        info!("post_prune hook: {}", command);
+        if pretend {
+            return Ok(());
+        }
+        let env = [
+            ("ZFS_SNAPPERS_HOOK", String::from("post_prune")),
+            ("ZFS_SNAPPERS_DATASET", dataset.into()),
+            ("ZFS_SNAPPERS_DESTROYED", destroyed.join(" ")),
+        ];
+        execute(command, &env, self.timeout())
+    }
+}
+
+/// The hooks of a dataset or group around one snapshot, with the environment describing it.
+///
+/// The commands see `ZFS_SNAPPERS_HOOK`, `ZFS_SNAPPERS_LABEL` and the space-separated
+/// `ZFS_SNAPPERS_DATASETS` and `ZFS_SNAPPERS_SNAPSHOTS`, of a single dataset also
+/// `ZFS_SNAPPERS_DATASET` and `ZFS_SNAPPERS_SNAPSHOT`. The post-hook sees
+/// `ZFS_SNAPPERS_STATUS`, 'created' or 'failed'.
+pub struct HookCall<'a> {
+    hooks: &'a Hooks,
+    env: Vec<(&'static str, String)>,
+}
+
+impl<'a> HookCall<'a> {
+    /// Hooks around the new `snapshots` of `datasets`, in the same order.
+    pub fn new(hooks: &'a Hooks, label: &str, datasets: &[&str], snapshots: &[String]) -> Self {
+        let mut env = vec![
+            ("ZFS_SNAPPERS_LABEL", label.to_owned()),
+            ("ZFS_SNAPPERS_DATASETS", datasets.join(" ")),
+            ("ZFS_SNAPPERS_SNAPSHOTS", snapshots.join(" ")),
+        ];
+        if let ([dataset], [snapshot]) = (datasets, snapshots) {
+            env.push(("ZFS_SNAPPERS_DATASET", dataset.to_string()));
+            env.push(("ZFS_SNAPPERS_SNAPSHOT", snapshot.clone()));
+        }
+        Self { hooks, env }
+    }
+
+    /// Whether a failing pre-hook aborts the snapshot.
+    pub fn aborts(&self) -> bool {
+        self.hooks.on_pre_failure.unwrap_or_default() == OnFailure::Abort
+    }
+
+    /// Runs the pre-hook, if any.
+    pub fn pre(&self, pretend: bool) -> Result<(), HookError> {
+        match &self.hooks.pre_snapshot {
+            Some(command) => self.run("pre_snapshot", command, &[], pretend),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the post-hook, if any.
+    pub fn post(&self, created: bool, pretend: bool) -> Result<(), HookError> {
+        let status = match created {
+            true => "created",
+            false => "failed",
+        };
+        match &self.hooks.post_snapshot {
+            Some(command) => self.run(
+                "post_snapshot",
+                command,
+                &[("ZFS_SNAPPERS_STATUS", status.into())],
+                pretend,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    fn run(
+        &self,
+        hook: &str,
+        command: &str,
+        extra: &[(&'static str, String)],
+        pretend: bool,
+    ) -> Result<(), HookError> {
+        info!("{} hook: {}", hook, command);
+        if pretend {
+            return Ok(());
+        }
+        let mut env = self.env.clone();
+        env.push(("ZFS_SNAPPERS_HOOK", hook.into()));
+        env.extend_from_slice(extra);
+        execute(command, &env, self.hooks.timeout())
+    }
+}
+
+/// Runs `command` by `sh -c` in a process group of its own, killing the group after
+/// `timeout`. Its stdout goes to stderr, stdout is kept for the report.
+fn execute(command: &str, env: &[(&str, String)], timeout: Duration) -> Result<(), HookError> {
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdout(io::stderr())
+        .process_group(0)
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            debug!("{}: {}", command, status);
+            return match status.success() {
+                true => Ok(()),
+                false => Err(HookError::Failed(command.into(), status)),
+            };
+        }
+        if Instant::now() >= deadline {
+            kill_group(&child)?;
+            child.wait()?;
+            return Err(HookError::Timeout(command.into(), timeout));
+        }
+        thread::sleep(POLL);
+    }
+}
+
+/// Kills `child` and everything it started. `child` leads its process group, so the id
+/// of the group is its own.
+fn kill_group(child: &process::Child) -> io::Result<()> {
+    match unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+pub enum HookError {
+    IOError(io::Error),
+    Failed(String, process::ExitStatus),
+    Timeout(String, Duration),
+}
+
+impl From<io::Error> for HookError {
+    fn from(e: io::Error) -> Self {
+        HookError::IOError(e)
+    }
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookError::IOError(e) => write!(f, "hook: {}", e),
+            HookError::Failed(c, status) => write!(f, "hook '{}' failed: {}", c, status),
+            HookError::Timeout(c, t) => {
+                write!(f, "hook '{}' killed after {}s", c, t.as_secs_f32())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn run_with_environment() {
+        let hooks = Hooks {
+            pre_snapshot: Some("test \"$ZFS_SNAPPERS_SNAPSHOT\" = tank/db@x".into()),
+            post_snapshot: Some("test \"$ZFS_SNAPPERS_STATUS\" = created".into()),
+            ..Hooks::default()
+        };
+        let call = HookCall::new(&hooks, "hourly", &["tank/db"], &["tank/db@x".into()]);
+        assert!(call.pre(false).is_ok());
+        assert!(call.post(true, false).is_ok());
+        assert!(call.post(false, false).is_err());
+        assert!(call.aborts());
+    }
+
+    #[test]
+    fn veto_by_exit_status() {
+        let hooks = Hooks {
+            pre_destroy: Some("test \"$ZFS_SNAPPERS_SNAPSHOT\" != tank/db@copying".into()),
+            ..Hooks::default()
+        };
+        assert!(matches!(
+            hooks.may_destroy("tank/db", "tank/db@x", false),
+            Ok(true)
+        ));
+        assert!(matches!(
+            hooks.may_destroy("tank/db", "tank/db@copying", false),
+            Ok(false)
+        ));
+        assert!(matches!(
+            hooks.may_destroy("tank/db", "tank/db@copying", true),
+            Ok(true)
+        ));
+    }
+
+    #[test]
+    fn kill_after_timeout() {
+        let started = Instant::now();
+        let result = execute("sleep 5", &[], Duration::from_millis(100));
+        assert!(matches!(result, Err(HookError::Timeout(_, _))));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(
+            execute("exit 3", &[], DEFAULT_TIMEOUT),
+            Err(HookError::Failed(_, _))
+        ));
+    }
+
+    #[test]
+    fn kill_started_commands_after_timeout() {
+        let marker = std::env::temp_dir().join(format!("zfs-snappers-hook-{}", process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let command = format!("(sleep 1; touch {}) & wait", marker.display());
+        let result = execute(&command, &[], Duration::from_millis(100));
+        assert!(matches!(result, Err(HookError::Timeout(_, _))));
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!marker.exists());
+    }
+}
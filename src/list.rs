@@ -0,0 +1,238 @@
+//! list
+//! Shows the managed snapshots of every dataset, grouped by label.
+use crate::config::Config;
+use crate::replicate::Replicator;
+use crate::report::Report;
+use crate::retention::{Age, Size};
+use crate::zfs::{FsType, Zfs, ZfsError, FS};
+use crate::{find_expendable, may_destroy, Output};
+use log::error;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Snapshot<'a> {
+    name: &'a str,
+    /// Creation-time, RFC 3339.
+    created: String,
+    age_seconds: i64,
+    used: u64,
+    /// Whether the next run would destroy the snapshot.
+    expendable: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Dataset<'a> {
+    dataset: &'a str,
+    labels: BTreeMap<&'a str, Vec<Snapshot<'a>>>,
+}
+
+/// Collects the managed snapshots of every dataset with auto-snapshots enabled. Expendable
+/// are the snapshots a run would destroy, after bookmarking the base of a replica and asking
+/// the pre-destroy hook, whose errors go to `report`.
+fn collect<'a>(
+    zfs: &'a Zfs,
+    config: &Config,
+    filesystems: &'a [FS],
+    (snapshots, bookmarks): (&'a [FS], &[FS]),
+    report: &mut Report,
+) -> Vec<Dataset<'a>> {
+    let now = zfs.now();
+    let replicator = config
+        .replication
+        .as_ref()
+        .map(|r| Replicator::new(zfs, r).pretending());
+    filesystems
+        .iter()
+        .filter(|fs| fs.managed())
+        .map(|fs| {
+            let labels: BTreeMap<&str, Vec<&FS>> = zfs
+                .labels()
+                .iter()
+                .map(|label| (label.as_str(), zfs.filter_snaps(Some(label), fs, snapshots)))
+                .filter(|(_, snaps)| !snaps.is_empty())
+                .collect();
+            let mut expendable: Vec<&FS> = Vec::new();
+            for label in labels.keys() {
+                let listed = (snapshots, bookmarks);
+                for sn in
+                    find_expendable(zfs, config, replicator.as_ref(), label, fs, listed, report)
+                {
+                    if !expendable.contains(&sn) {
+                        expendable.push(sn);
+                    }
+                }
+            }
+            let expendable: HashSet<&str> = expendable
+                .into_iter()
+                .filter(|sn| may_destroy(zfs, config, sn, report))
+                .map(|sn| sn.name.as_str())
+                .collect();
+            let labels = labels
+                .into_iter()
+                .map(|(label, snaps)| {
+                    let snaps = snaps
+                        .into_iter()
+                        .map(|sn| Snapshot {
+                            name: &sn.name,
+                            created: sn.date().to_rfc3339(),
+                            age_seconds: (now - sn.date()).num_seconds(),
+                            used: sn.used(),
+                            expendable: expendable.contains(sn.name.as_str()),
+                        })
+                        .collect();
+                    (label, snaps)
+                })
+                .collect();
+            Dataset {
+                dataset: &fs.name,
+                labels,
+            }
+        })
+        .collect()
+}
+
+fn print_table(datasets: &[Dataset]) {
+    for ds in datasets {
+        println!("{}", ds.dataset);
+        for (label, snaps) in &ds.labels {
+            println!("  {} ({})", label, snaps.len());
+            for sn in snaps {
+                println!(
+                    "    {:<48} {:>6} {:>11}  {}",
+                    sn.name.split_once('@').map_or(sn.name, |(_, s)| s),
+                    Age(chrono::Duration::seconds(sn.age_seconds)).to_string(),
+                    Size(sn.used).to_string(),
+                    if sn.expendable { "prune" } else { "" }
+                );
+            }
+        }
+    }
+}
+
+/// The labels of all snapshots named by the template or adopted, for `list` without labels.
+pub fn labels(zfs: &Zfs) -> Result<Vec<String>, ZfsError> {
+    let snapshots = zfs.list_filesystems(FsType::Snapshot)?;
+    Ok(zfs.labels_of(&snapshots).into_iter().collect())
+}
+
+/// Prints the managed snapshots of every dataset with auto-snapshots enabled, grouped by
+/// label, with age, used size and whether the next run would destroy them.
+pub fn run(zfs: &Zfs, config: &Config, output: Output) -> bool {
+    let listed = zfs
+        .list_filesystems(FsType::Filesystem)
+        .and_then(|filesystems| {
+            let snapshots = zfs.list_filesystems(FsType::Snapshot)?;
+            let bookmarks = match config.replication {
+                Some(_) => zfs.list_filesystems(FsType::Bookmark)?,
+                None => Vec::new(),
+            };
+            Ok((filesystems, snapshots, bookmarks))
+        });
+    let (filesystems, snapshots, bookmarks) = match listed {
+        Ok(listed) => listed,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let mut report = Report::new(zfs.now().to_rfc3339(), zfs.pretend());
+    let listed = (&snapshots[..], &bookmarks[..]);
+    let datasets = collect(zfs, config, &filesystems, listed, &mut report);
+    match output {
+        Output::Text => print_table(&datasets),
+        Output::Json => match serde_json::to_string_pretty(&datasets) {
+            Ok(json) => println!("{json}"),
+            Err(e) => {
+                error!("{}", e);
+                return false;
+            }
+        },
+    }
+    report.success()
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::fake::{self, FakePool};
+
+    #[test]
+    fn group_by_label_and_mark_expendable() {
+        let now = fake::now();
+        let pool = fake::www()
+            .snapped("tank/www", "hourly", 2, 2048)
+            .snapped("tank/www", "hourly", 1, 10)
+            .snapped("tank/www", "daily", 12, 10)
+            .snapshot("tank/www@manual", now, 10);
+        let zfs = Zfs::new(
+            false,
+            "zfs-snappers",
+            vec!["daily".into(), "hourly".into()],
+            now,
+        )
+        .with_backend(pool);
+        let config = Config::parse("keep = 1").ok().unwrap();
+        let filesystems = zfs.list_filesystems(FsType::Filesystem).ok().unwrap();
+        let snapshots = zfs.list_filesystems(FsType::Snapshot).ok().unwrap();
+        let mut report = Report::new(String::new(), false);
+        let datasets = collect(&zfs, &config, &filesystems, (&snapshots, &[]), &mut report);
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(
+            datasets[0].labels.keys().copied().collect::<Vec<_>>(),
+            ["daily", "hourly"]
+        );
+        let hourly = &datasets[0].labels["hourly"];
+        assert_eq!(
+            hourly[0],
+            Snapshot {
+                name: "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                created: String::from("2022-07-12T10:00:00+00:00"),
+                age_seconds: 7200,
+                used: 2048,
+                expendable: true,
+            }
+        );
+        assert!(!hourly[1].expendable);
+        assert!(!datasets[0].labels["daily"][0].expendable);
+    }
+
+    #[test]
+    fn keep_vetoed_snapshots_without_bookmarking() {
+        let pool = fake::www()
+            .snapped("tank/www", "hourly", 2, 2048)
+            .snapped("tank/www", "hourly", 1, 10);
+        let zfs = Zfs::new(false, "zfs-snappers", vec!["hourly".into()], fake::now())
+            .with_backend(pool.clone());
+        let filesystems = zfs.list_filesystems(FsType::Filesystem).ok().unwrap();
+        let snapshots = zfs.list_filesystems(FsType::Snapshot).ok().unwrap();
+        let expendable = |content: &str| {
+            let config = Config::parse(content).ok().unwrap();
+            let mut report = Report::new(String::new(), false);
+            let datasets = collect(&zfs, &config, &filesystems, (&snapshots, &[]), &mut report);
+            datasets[0].labels["hourly"][0].expendable
+        };
+        assert!(!expendable("keep = 1\n[hooks]\npre_destroy = \"false\""));
+        // The unreachable target makes a run bookmark every expendable snapshot.
+        assert!(expendable(
+            "keep = 1\n[replication]\ntarget = \"backup\"\ncommand = \"false\""
+        ));
+        assert!(pool.calls().is_empty());
+    }
+
+    #[test]
+    fn find_labels_in_the_names_of_the_snapshots() {
+        let now = fake::now();
+        let pool = FakePool::new(now)
+            .filesystem("tank/www", &[("com.sun:auto-snapshot", "true")])
+            .snapshot("tank/www@zfs-snappers_hourly-2022-07-12-1100", now, 10)
+            .snapshot("tank/www@zfs-snappers_daily-2022-07-12-0000", now, 10)
+            .snapshot("tank/www@other_weekly-2022-07-11-0000", now, 10)
+            .snapshot("tank/www@manual", now, 10);
+        let zfs = Zfs::new(false, "zfs-snappers", Vec::new(), now).with_backend(pool);
+        assert_eq!(
+            labels(&zfs).ok(),
+            Some(vec!["daily".into(), "hourly".into()])
+        );
+    }
+}
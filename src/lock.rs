@@ -0,0 +1,125 @@
+//! lock
+//! Keeps several instances from creating and destroying snapshots at the same time.
+use crate::retention::Age;
+use chrono::Duration;
+use fs2::FileExt;
+use log::debug;
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Instant;
+
+/// Interval to retry a lock held by another instance.
+const RETRY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// What a lock protects.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// One lock for all pools.
+    #[default]
+    Global,
+    /// One lock per pool, instances working on different pools run concurrently.
+    Pool,
+}
+
+/// Settings of the lock files.
+///
+/// ```toml
+/// [lock]
+/// dir = "/run/lock"
+/// scope = "pool"
+/// wait = "10m"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields, default)]
+pub struct LockConfig {
+    /// Directory of the lock files.
+    pub dir: PathBuf,
+    pub scope: Scope,
+    /// How long to wait for another instance, '0s' skips immediately.
+    pub wait: Age,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("/run/lock"),
+            scope: Scope::Global,
+            wait: Age(Duration::minutes(10)),
+        }
+    }
+}
+
+impl LockConfig {
+    /// The lock file protecting `pool`.
+    pub fn path(&self, pool: &str) -> PathBuf {
+        match self.scope {
+            Scope::Global => self.dir.join("zfs-snappers.lock"),
+            Scope::Pool => self.dir.join(format!("zfs-snappers-{pool}.lock")),
+        }
+    }
+}
+
+/// An exclusive `flock` on a file, released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Locks `path`, waiting up to `wait` for another instance to release it. Returns `None`
+    /// if it is still held.
+    pub fn acquire(path: &Path, wait: Age) -> io::Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        let deadline = Instant::now() + wait.0.to_std().unwrap_or_default();
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => {
+                    debug!("locked {}", path.display());
+                    return Ok(Some(Self { _file: file }));
+                }
+                Err(e) if e.kind() != fs2::lock_contended_error().kind() => return Err(e),
+                Err(_) if Instant::now() >= deadline => return Ok(None),
+                Err(_) => thread::sleep(RETRY),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn wait_for_another_instance() {
+        let path = env::temp_dir().join(format!("zfs-snappers-{}.lock", std::process::id()));
+        let wait = Age(Duration::milliseconds(300));
+        let first = Lock::acquire(&path, wait).unwrap();
+        assert!(first.is_some());
+        let started = Instant::now();
+        assert!(Lock::acquire(&path, wait).unwrap().is_none());
+        assert!(started.elapsed() >= wait.0.to_std().unwrap());
+        drop(first);
+        assert!(Lock::acquire(&path, wait).unwrap().is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn name_lock_files_by_scope() {
+        let mut config = LockConfig::default();
+        assert_eq!(config.path("tank"), config.path("backup"));
+        config.scope = Scope::Pool;
+        assert_eq!(
+            config.path("tank"),
+            PathBuf::from("/run/lock/zfs-snappers-tank.lock")
+        );
+    }
+}
@@ -1,49 +1,196 @@
-///! # zfs-snappers
-///! A zfs-auto-snapshot like tool written in Rust.
-///!
-///! License: MIT
-///! (c) migmedia 2020 - 2022
+//! # zfs-snappers
+//! A zfs-auto-snapshot like tool written in Rust.
+//!
+//! License: MIT
+//! (c) migmedia 2020 - 2022
+mod backend;
+mod check;
+mod config;
+mod daemon;
+#[cfg(test)]
+mod fake;
+mod hooks;
+mod list;
+mod lock;
+mod metrics;
+mod name;
+mod replicate;
+mod report;
+mod retention;
+mod samba;
+mod schedule;
 mod zfs;
+mod zone;
 
-use crate::zfs::{FsType, Zfs};
+use crate::config::{Config, ConfigError, Overrides};
+use crate::hooks::HookCall;
+use crate::lock::Lock;
+use crate::replicate::Replicator;
+use crate::report::{Report, Status};
+use crate::retention::{Age, Size};
+use crate::zfs::{FsType, Zfs, FS};
 use chrono::{DateTime, Utc};
-use clap::Parser;
-use log::{debug, error};
-use simplelog::{ColorChoice, CombinedLogger, Config, LevelFilter, TermLogger, TerminalMode};
+use clap::{CommandFactory, ErrorKind, Parser, Subcommand, ValueEnum};
+use log::{debug, error, info, warn};
+use simplelog::{
+    ColorChoice, CombinedLogger, Config as LogConfig, LevelFilter, TermLogger, TerminalMode,
+};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
 
 #[derive(Parser)]
 #[clap(version, about, long_about = None)]
+#[clap(subcommand_negates_reqs = true, subcommand_precedence_over_arg = true)]
 struct Opt {
     /// Prints info messages.
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     pub verbose: bool,
 
     /// Prints debug messages.
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     pub debug: bool,
 
-    /// Label of snapshot usually 'hourly', 'daily', or 'monthly'.
-    pub label: String,
+    /// Configuration-file [default: /etc/zfs-snappers.toml].
+    #[clap(short, long, global = true, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /// Labels of snapshots usually 'hourly', 'daily', or 'monthly'.
+    #[clap(required_unless_present = "all")]
+    pub labels: Vec<String>,
+
+    /// Handles all labels of the configuration-file.
+    #[clap(short, long)]
+    pub all: bool,
+
+    /// Min size written since the last snapshot, like '10M' or '1G', a bare number counts
+    /// Kibibytes. Overrides the configuration-file.
+    #[clap(short = 'm', long, global = true)]
+    pub min_size: Option<Size>,
 
-    /// Min size in Kilo-Byte.
-    #[clap(short = 'm', long, default_value = "0")]
-    pub min_size: usize,
+    /// Keeps NUM recent snapshots and destroy older snapshots, overrides the configuration-file.
+    #[clap(name = "NUM", short, long = "keep", global = true)]
+    pub keep: Option<usize>,
 
-    /// Keeps NUM recent snapshots and destroy older snapshots.
-    #[clap(name = "NUM", short, long = "keep", default_value = "8")]
-    pub keep: usize,
+    /// Keeps all snapshots younger than AGE (e.g. '48h'), overrides the configuration-file.
+    #[clap(long, global = true, value_name = "AGE")]
+    pub keep_within: Option<Age>,
 
-    /// Prefix of snapshots.
-    #[clap(short, long, default_value = "zfs-snappers")]
-    pub prefix: String,
+    /// Destroys snapshots older than AGE (e.g. '90d'), overrides the configuration-file.
+    #[clap(long, global = true, value_name = "AGE")]
+    pub max_age: Option<Age>,
+
+    /// Prefix of snapshots, overrides the configuration-file.
+    #[clap(short, long, global = true)]
+    pub prefix: Option<String>,
 
     /// Pretending, not really changing anything.
-    #[clap(short = 'n', long)]
+    #[clap(short = 'n', long, global = true)]
     pub dry_run: bool,
+
+    /// Format of the output.
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    pub output: Output,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Format of what is printed to stdout.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Keeps running and creates the snapshots of every label when it is due.
+    Daemon {
+        /// Labels to schedule [default: all labels of the configuration-file].
+        labels: Vec<String>,
+    },
+    /// Sends the newest snapshot of every managed dataset to the replication target.
+    Replicate {
+        /// Dataset receiving the replicas, overrides the configuration-file.
+        #[clap(short, long)]
+        target: Option<String>,
+    },
+    /// Shows the managed snapshots of every dataset with age, size and whether they are expendable
+    List {
+        /// Labels to show [default: all labels found in the names of the snapshots].
+        labels: Vec<String>,
+    },
+    /// Prints smb.conf-shares offering the snapshots as "Previous Versions" by shadow_copy2
+    SmbConf,
+    /// Monitoring-plugin: checks that every dataset has a recent snapshot of a label
+    Check {
+        /// Label of the snapshots.
+        label: String,
+        /// Warns if the newest snapshot of a dataset is older than AGE (e.g. '2h').
+        #[clap(long, value_name = "AGE")]
+        warning: Option<Age>,
+        /// Critical if the newest snapshot of a dataset is older than AGE, or missing.
+        #[clap(long, value_name = "AGE")]
+        critical: Age,
+    },
+}
+
+/// Parses the command line. Labels given with a subcommand are rejected, instead of taking
+/// the subcommand for a label or the other way round.
+fn parse_args<I, T>(args: I) -> Result<Opt, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let opt = Opt::try_parse_from(args)?;
+    if opt.command.is_some() && (!opt.labels.is_empty() || opt.all) {
+        return Err(Opt::command().error(
+            ErrorKind::ArgumentConflict,
+            "labels and --all can not be combined with a subcommand",
+        ));
+    }
+    Ok(opt)
+}
+
+/// Reads the configuration-file and applies the command line options.
+fn load_config(opt: &Opt) -> Result<Config, ConfigError> {
+    let mut config = Config::load(opt.config.as_deref())?;
+    if let Some(prefix) = &opt.prefix {
+        config.prefix = prefix.clone();
+    }
+    config.cli = Overrides {
+        keep: opt.keep,
+        min_size: opt.min_size,
+        keep_within: opt.keep_within,
+        max_age: opt.max_age,
+    };
+    config::check_name("prefix", &config.prefix)?;
+    Ok(config)
+}
+
+/// Whether `args` call the subcommand `check`, also if they are invalid otherwise.
+fn calls_check(args: &[OsString]) -> bool {
+    Opt::command()
+        .ignore_errors(true)
+        .try_get_matches_from(args)
+        .is_ok_and(|matches| matches.subcommand_name() == Some("check"))
 }
 
 fn main() {
-    let opt = Opt::parse();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let opt = parse_args(&args).unwrap_or_else(|e| {
+        // Monitoring expects the plugin-state UNKNOWN for a bad invocation, not CRITICAL.
+        if e.use_stderr() && calls_check(&args) {
+            let _ = e.print();
+            println!("ZFS-SNAPPERS UNKNOWN - invalid arguments");
+            process::exit(check::State::Unknown as i32);
+        }
+        e.exit()
+    });
+    // Logs go to stderr, stdout is kept for the JSON report, `list` and `check`.
     CombinedLogger::init(vec![TermLogger::new(
         match opt.debug {
             true => LevelFilter::Debug,
@@ -52,42 +199,968 @@ fn main() {
                 false => LevelFilter::Error,
             },
         },
-        Config::default(),
-        TerminalMode::Mixed,
+        LogConfig::default(),
+        TerminalMode::Stderr,
         ColorChoice::Auto,
     )])
     .unwrap();
+    if let Some(Command::Daemon { labels }) = &opt.command {
+        if let Err(e) = daemon::run(labels, opt.dry_run, || load_config(&opt)) {
+            error!("{}", e);
+            process::exit(1);
+        }
+        return;
+    }
+    let config = match load_config(&opt) {
+        Ok(config) => config,
+        // Monitoring expects the plugin-state UNKNOWN.
+        Err(e) if matches!(opt.command, Some(Command::Check { .. })) => {
+            println!("ZFS-SNAPPERS UNKNOWN - {e}");
+            process::exit(check::State::Unknown as i32);
+        }
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(Command::Replicate { target }) = &opt.command {
+        let mut replication = config.replication.clone().unwrap_or_default();
+        if let Some(target) = target {
+            replication.target = target.clone();
+        }
+        if let Err(e) = config::check_dataset(&replication.target) {
+            error!("{}, configure a replication target", e);
+            process::exit(1);
+        }
+        let labels = config.labels.keys().cloned().collect();
+        let zfs = Zfs::from_config(&config, opt.dry_run, labels, Utc::now());
+        if !replicate::run(&zfs, &replication) {
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::List { labels }) = &opt.command {
+        let labels = match labels.is_empty() {
+            true => {
+                let zfs = Zfs::from_config(&config, opt.dry_run, Vec::new(), Utc::now());
+                list::labels(&zfs).unwrap_or_else(|e| {
+                    error!("{}", e);
+                    process::exit(1);
+                })
+            }
+            false => labels.clone(),
+        };
+        let zfs = Zfs::from_config(&config, opt.dry_run, labels, Utc::now());
+        if !list::run(&zfs, &config, opt.output) {
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::SmbConf) = &opt.command {
+        let labels = config.labels.keys().cloned().collect();
+        let zfs = Zfs::from_config(&config, opt.dry_run, labels, Utc::now());
+        if !samba::run(&zfs, &config) {
+            process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Check {
+        label,
+        warning,
+        critical,
+    }) = &opt.command
+    {
+        if let Err(e) = config::check_name("label", label) {
+            println!("ZFS-SNAPPERS UNKNOWN - {e}");
+            process::exit(check::State::Unknown as i32);
+        }
+        let zfs = Zfs::from_config(&config, false, vec![label.clone()], Utc::now());
+        let (state, line) = check::check(&zfs, label, *warning, *critical);
+        println!("{line}");
+        process::exit(state as i32);
+    }
+    let mut labels = opt.labels;
+    if opt.all {
+        labels.extend(config.labels.keys().cloned());
+    }
+    labels.sort_unstable();
+    labels.dedup();
+    if labels.is_empty() {
+        error!("no labels given and none configured");
+        process::exit(1);
+    }
+    if let Err(e) = labels
+        .iter()
+        .try_for_each(|label| config::check_name("label", label))
+    {
+        error!("{}", e);
+        process::exit(1);
+    }
+    debug!("{:?}", config);
     let now: DateTime<Utc> = Utc::now();
-    let zfs = Zfs::new(
-        opt.dry_run,
-        opt.prefix,
-        opt.label,
-        opt.keep,
-        now.format("%Y-%m-%d-%H%M").to_string(),
-    );
-    let snapshots = zfs.list_filesystems(FsType::Snapshot);
-    for fs in zfs
-        .list_filesystems(FsType::Filesystem)
+    let zfs = Zfs::from_config(&config, opt.dry_run, labels, now);
+    let report = run(&zfs, &config);
+    if opt.output == Output::Json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{json}"),
+            Err(e) => error!("{}", e),
+        }
+    }
+    if !report.success() {
+        process::exit(1);
+    }
+}
+
+/// Lists filesystems and snapshots once, then creates and prunes the snapshots of every
+/// label handled by `zfs`, then writes the metrics. Returns the report of the run.
+fn run(zfs: &Zfs, config: &Config) -> Report {
+    let mut report = Report::new(zfs.now().to_rfc3339(), zfs.pretend());
+    let listing = execute(zfs, config, &mut report);
+    let mut report = report.finish();
+    if let Some(metrics) = &config.metrics {
+        let listed = listing.listed.as_ref().map(|(f, s)| (&f[..], &s[..]));
+        if let Err(e) = metrics::write(zfs, config, &metrics.path, &report, listed) {
+            report.error(&[], "metrics", e);
+        }
+    }
+    report
+}
+
+/// The filesystems and snapshots `execute` listed before changing anything, `None` if the
+/// listing failed.
+#[derive(Default)]
+struct Listing {
+    _locks: Vec<Lock>,
+    listed: Option<(Vec<FS>, Vec<FS>)>,
+}
+
+fn execute(zfs: &Zfs, config: &Config, report: &mut Report) -> Listing {
+    // Keeps the incremental base of replicas by bookmarks.
+    let replicator = config.replication.as_ref().map(|r| Replicator::new(zfs, r));
+    let started = Instant::now();
+    let filesystems = match zfs.list_filesystems(FsType::Filesystem) {
+        Ok(filesystems) => filesystems,
+        Err(e) => {
+            report.error(&[], "list", e);
+            return Listing::default();
+        }
+    };
+    // Snapshots are listed after locking, another instance may just be changing them.
+    let (locks, locked) = lock_pools(zfs, config, &filesystems, report);
+    for fs in filesystems
         .iter()
-        .filter(|f| f.snap)
+        .filter(|fs| !locked.contains(pool(&fs.name)))
     {
-        if !zfs.next_snapshot_needed(opt.min_size, fs, &snapshots) {
-            debug!("skip FS: {:?}", fs);
-            continue;
-        }
-        let exp_fs = zfs.find_expendable_snapshots(fs, &snapshots);
-        debug!("FS: {:?}", fs);
-        match zfs.create_snapshot(fs) {
-            Ok(()) => {
-                debug!("Created! {:?}", &exp_fs);
-                for exp in exp_fs {
-                    match zfs.remove_snapshot(exp) {
-                        Ok(()) => {}
-                        Err(e) => error!("{}", e),
-                    };
+        for label in zfs.labels().iter().filter(|label| fs.snap_for(label)) {
+            report.skipped(&fs.name, label, report::LOCKED.into());
+        }
+    }
+    let listed = zfs
+        .list_filesystems(FsType::Snapshot)
+        .and_then(|snapshots| {
+            let bookmarks = match replicator {
+                Some(_) => zfs.list_filesystems(FsType::Bookmark)?,
+                None => Vec::new(),
+            };
+            Ok((snapshots, bookmarks))
+        });
+    let (snapshots, bookmarks) = match listed {
+        Ok(listed) => listed,
+        Err(e) => {
+            report.error(&[], "list", e);
+            return Listing {
+                _locks: locks,
+                listed: None,
+            };
+        }
+    };
+    debug!("listed in {:?}", started.elapsed());
+    // The bytes written since the snapshots of all labels, by one `zfs get` per dataset.
+    let mut written = HashMap::new();
+    for fs in filesystems
+        .iter()
+        .filter(|fs| locked.contains(pool(&fs.name)) && zfs.labels().iter().any(|l| fs.snap_for(l)))
+    {
+        match zfs.written_since(fs, &snapshots) {
+            Ok(bytes) => {
+                written.insert(fs.name.as_str(), bytes);
+            }
+            Err(e) => {
+                let message = format!("{}, snapping {} anyway", e, fs.name);
+                report.error(&[&fs.name], "written", message);
+            }
+        }
+    }
+    // With grandfather-father-son buckets every label prunes the snapshots of all labels.
+    let mut destroyed = HashSet::new();
+    let mut expendable = Vec::new();
+    for label in zfs.labels() {
+        let candidates: Vec<&FS> = filesystems
+            .iter()
+            .filter(|f| f.snap_for(label) && locked.contains(pool(&f.name)))
+            .collect();
+        let mut needed = HashSet::new();
+        // Bytes written to the datasets below their min_size.
+        let mut below = HashMap::new();
+        for fs in &candidates {
+            let min_size = config.policy(label, &fs.name).min_size;
+            // Without the written bytes it is snapped anyway.
+            let checked = written
+                .get(fs.name.as_str())
+                .map(|written| zfs.next_snapshot_needed(label, min_size, written));
+            match checked {
+                Some((false, written)) => {
+                    below.insert(fs.name.as_str(), written.unwrap_or_default());
+                }
+                _ => {
+                    needed.insert(fs.name.as_str());
                 }
             }
-            Err(e) => error!("{}", e),
         }
+        // A consistency group is snapped as a whole as soon as one of its datasets is due.
+        let due_groups: HashSet<&str> = needed.iter().filter_map(|n| config.group(n)).collect();
+        let mut batches: Vec<(Option<&str>, Vec<&FS>)> = Vec::new();
+        for fs in candidates {
+            match config.group(&fs.name) {
+                Some(group) if due_groups.contains(group) => {
+                    match batches.iter_mut().find(|(g, _)| *g == Some(group)) {
+                        Some((_, batch)) => batch.push(fs),
+                        None => batches.push((Some(group), vec![fs])),
+                    }
+                }
+                None if needed.contains(fs.name.as_str()) => batches.push((None, vec![fs])),
+                _ => {
+                    debug!("skip FS: {:?}", fs);
+                    let min_size = config.policy(label, &fs.name).min_size;
+                    let reason = format!(
+                        "{} written, below min_size {}",
+                        Size(below[fs.name.as_str()]),
+                        min_size
+                    );
+                    report.skipped(&fs.name, label, reason);
+                }
+            }
+        }
+        for (group, batch) in batches {
+            debug!("FS: {:?}", batch);
+            let listed = (&filesystems[..], &snapshots[..]);
+            if !create(zfs, config, label, group, &batch, listed, report) {
+                continue;
+            }
+            for fs in batch {
+                let listed = (&snapshots[..], &bookmarks[..]);
+                let exp_fs =
+                    find_expendable(zfs, config, replicator.as_ref(), label, fs, listed, report);
+                debug!("Created! {:?}", &exp_fs);
+                expendable.extend(
+                    exp_fs
+                        .into_iter()
+                        .filter(|exp| destroyed.insert(exp.name.as_str())),
+                );
+            }
+        }
+    }
+    prune(zfs, config, &expendable, report);
+    Listing {
+        _locks: locks,
+        listed: Some((filesystems, snapshots)),
+    }
+}
+
+/// The snapshots of `fs` a run of `label` destroys after its snapshot, bookmarking the
+/// incremental base of the replica of `fs`.
+fn find_expendable<'a>(
+    zfs: &'a Zfs,
+    config: &Config,
+    replicator: Option<&Replicator>,
+    label: &str,
+    fs: &FS,
+    (snapshots, bookmarks): (&'a [FS], &[FS]),
+    report: &mut Report,
+) -> Vec<&'a FS> {
+    let policy = |label: &str| config.policy(label, &fs.name);
+    let mut expendable = zfs.find_expendable_snapshots(label, policy, fs, snapshots);
+    if let Some(replicator) = replicator {
+        if let Err(e) = replicator.protect_base(fs, snapshots, bookmarks, &mut expendable) {
+            report.error(&[&fs.name], "bookmark", e);
+        }
+    }
+    expendable
+}
+
+/// Whether the pre-destroy hook of the dataset of `sn` allows destroying it.
+fn may_destroy(zfs: &Zfs, config: &Config, sn: &FS, report: &mut Report) -> bool {
+    let dataset = sn.dataset();
+    let hooks = match config.hooks(dataset) {
+        Some(hooks) => hooks,
+        None => return true,
+    };
+    let started = Instant::now();
+    let verdict = hooks.may_destroy(dataset, &sn.name, zfs.pretend());
+    report.time(&[dataset], "pre_destroy", started);
+    match verdict {
+        Ok(true) => true,
+        Ok(false) => {
+            info!("{} vetoed by the pre_destroy hook", sn.name);
+            report.vetoed(dataset, &sn.name);
+            false
+        }
+        Err(e) => {
+            report.error(
+                &[dataset],
+                "pre_destroy",
+                format!("{}, keeping {}", e, sn.name),
+            );
+            false
+        }
+    }
+}
+
+/// Destroys the expendable snapshots not vetoed by the pre-destroy hook, per dataset at
+/// once, then runs the post-prune hooks of the datasets.
+fn prune(zfs: &Zfs, config: &Config, expendable: &[&FS], report: &mut Report) {
+    let mut allowed: BTreeMap<&str, Vec<&FS>> = BTreeMap::new();
+    for &sn in expendable {
+        let may = may_destroy(zfs, config, sn, report);
+        let snaps = allowed.entry(sn.dataset()).or_default();
+        if may {
+            snaps.push(sn);
+        }
+    }
+    for (dataset, snaps) in allowed {
+        let started = Instant::now();
+        let (destroyed, errors) = zfs.remove_snapshots(&snaps);
+        report.time(&[dataset], "destroy", started);
+        for e in errors {
+            report.error(&[dataset], "destroy", e);
+        }
+        let destroyed: Vec<&str> = destroyed.iter().map(|sn| sn.name.as_str()).collect();
+        for sn in &destroyed {
+            report.destroyed(dataset, sn);
+        }
+        if let Some(hooks) = config.hooks(dataset) {
+            let started = Instant::now();
+            if let Err(e) = hooks.post_prune(dataset, &destroyed, zfs.pretend()) {
+                report.error(&[dataset], "post_prune", e);
+            }
+            report.time(&[dataset], "post_prune", started);
+        }
+    }
+}
+
+/// Name of the pool of a dataset.
+fn pool(dataset: &str) -> &str {
+    dataset.split('/').next().unwrap_or(dataset)
+}
+
+/// Locks the pools of `filesystems`, unless pretending. Returns the locks, to hold until the
+/// run is finished, and the locked pools. Pools locked by another instance are skipped.
+fn lock_pools(
+    zfs: &Zfs,
+    config: &Config,
+    filesystems: &[FS],
+    report: &mut Report,
+) -> (Vec<Lock>, HashSet<String>) {
+    let pools: HashSet<&str> = filesystems.iter().map(|fs| pool(&fs.name)).collect();
+    if zfs.pretend() {
+        return (Vec::new(), pools.into_iter().map(String::from).collect());
+    }
+    let mut paths: BTreeMap<PathBuf, Vec<&str>> = BTreeMap::new();
+    for pool in pools {
+        paths.entry(config.lock.path(pool)).or_default().push(pool);
+    }
+    let mut locks = Vec::new();
+    let mut locked = HashSet::new();
+    for (path, mut pools) in paths {
+        pools.sort_unstable();
+        match Lock::acquire(&path, config.lock.wait) {
+            Ok(Some(lock)) => {
+                locks.push(lock);
+                locked.extend(pools.into_iter().map(String::from));
+            }
+            Ok(None) => warn!(
+                "{} is held by another instance, skipping {}",
+                path.display(),
+                pools.join(", ")
+            ),
+            Err(e) => report.error(&[], "lock", format!("lock {}: {}", path.display(), e)),
+        }
+    }
+    (locks, locked)
+}
+
+/// Creates the snapshots of a batch between the hooks of its group and datasets. Returns
+/// whether they were created.
+fn create(
+    zfs: &Zfs,
+    config: &Config,
+    label: &str,
+    group: Option<&str>,
+    batch: &[&FS],
+    (filesystems, snapshots): (&[FS], &[FS]),
+    report: &mut Report,
+) -> bool {
+    let datasets: Vec<&str> = batch.iter().map(|fs| fs.name.as_str()).collect();
+    let snapshot = zfs.snapshot_name(label, batch, snapshots);
+    let names: Vec<String> = datasets
+        .iter()
+        .map(|dataset| format!("{dataset}@{snapshot}"))
+        .collect();
+    let hooks: Vec<_> = datasets
+        .iter()
+        .map(|dataset| config.hooks(dataset))
+        .collect();
+    // Every hook with the datasets it is run for.
+    let mut calls = Vec::new();
+    if let Some(hooks) = group.and_then(|g| config.groups[g].hooks.as_ref()) {
+        calls.push((
+            HookCall::new(hooks, label, &datasets, &names),
+            &datasets[..],
+        ));
+    }
+    for (i, hooks) in hooks.iter().enumerate() {
+        if let Some(hooks) = hooks {
+            let call = HookCall::new(hooks, label, &datasets[i..=i], &names[i..=i]);
+            calls.push((call, &datasets[i..=i]));
+        }
+    }
+    let mut ran = Vec::new();
+    for (call, hooked) in &calls {
+        let started = Instant::now();
+        let result = call.pre(zfs.pretend());
+        report.time(hooked, "pre_snapshot", started);
+        if let Err(e) = result {
+            report.error(hooked, "pre_snapshot", e);
+            if call.aborts() {
+                break;
+            }
+        }
+        ran.push((call, hooked));
+    }
+    let created = ran.len() == calls.len() && {
+        let started = Instant::now();
+        let result = zfs.create_snapshots(&snapshot, batch, filesystems);
+        report.time(&datasets, "snapshot", started);
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                report.error(&datasets, "snapshot", e);
+                false
+            }
+        }
+    };
+    let status = match created {
+        true => Status::Created,
+        false => Status::Failed,
+    };
+    for (dataset, name) in datasets.iter().zip(names) {
+        report.snapshot(dataset, label, status, Some(name));
+    }
+    // Resumes what the pre-hooks stopped, also without a snapshot.
+    for (call, hooked) in ran.iter().rev() {
+        let started = Instant::now();
+        if let Err(e) = call.post(created, zfs.pretend()) {
+            report.error(hooked, "post_snapshot", e);
+        }
+        report.time(hooked, "post_snapshot", started);
+    }
+    created
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::fake::{self, now, FakePool};
+    use chrono::Duration;
+
+    fn pool() -> FakePool {
+        fake::www()
+            .filesystem("tank/db", &[("com.sun:auto-snapshot:daily", "true")])
+            .snapped("tank/www", "hourly", 3, 2048)
+            .snapped("tank/www", "hourly", 2, 2048)
+            .snapped("tank/www", "hourly", 1, 10)
+    }
+
+    fn zfs(pool: &FakePool, pretend: bool, labels: &[&str]) -> Zfs {
+        let labels = labels.iter().map(|l| l.to_string()).collect();
+        Zfs::new(pretend, "zfs-snappers", labels, now()).with_backend(pool.clone())
+    }
+
+    #[test]
+    fn reject_labels_with_subcommands() {
+        let parse = |args: &str| parse_args(args.split_whitespace());
+        assert!(parse("zfs-snappers -v hourly list").is_err());
+        assert!(parse("zfs-snappers -n daily check hourly --critical 4h").is_err());
+        assert!(parse("zfs-snappers --all list").is_err());
+        assert!(matches!(
+            parse("zfs-snappers -v list").map(|o| o.command),
+            Ok(Some(Command::List { .. }))
+        ));
+        assert_eq!(
+            parse("zfs-snappers -n hourly daily").map(|o| o.labels).ok(),
+            Some(vec![String::from("hourly"), String::from("daily")])
+        );
+    }
+
+    #[test]
+    fn recognise_invalid_calls_of_check() {
+        let args = |args: &str| {
+            args.split_whitespace()
+                .map(OsString::from)
+                .collect::<Vec<_>>()
+        };
+        assert!(calls_check(&args("zfs-snappers check hourly")));
+        assert!(calls_check(&args(
+            "zfs-snappers -v check hourly --critical 4"
+        )));
+        assert!(calls_check(&args(
+            "zfs-snappers check hourly --critical 4h"
+        )));
+        assert!(!calls_check(&args("zfs-snappers list")));
+        assert!(!calls_check(&args("zfs-snappers -n hourly")));
+    }
+
+    fn config(content: &str) -> Config {
+        let mut config = Config::parse(content).ok().unwrap();
+        config.lock.dir = fake::lock_dir();
+        config
+    }
+
+    #[test]
+    fn create_and_prune() {
+        let pool = pool();
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config("keep = 2")).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200"
+            ]
+        );
+    }
+
+    #[test]
+    fn report_every_dataset() {
+        let pool = pool();
+        let report = run(
+            &zfs(&pool, false, &["hourly", "daily"]),
+            &config("keep = 2\n[labels.daily]\nmin_size = \"1M\""),
+        );
+        assert!(report.success());
+        let www = &report.datasets["tank/www"];
+        assert_eq!(www.snapshots[0].status, Status::Created);
+        assert_eq!(
+            www.snapshots[0].name.as_deref(),
+            Some("tank/www@zfs-snappers_hourly-2022-07-12-1200")
+        );
+        assert_eq!(
+            www.destroyed,
+            ["tank/www@zfs-snappers_hourly-2022-07-12-0900"]
+        );
+        assert!(www.seconds.contains_key("snapshot"));
+        assert!(www.seconds.contains_key("destroy"));
+        let db = &report.datasets["tank/db"];
+        assert_eq!(db.snapshots[0].status, Status::Created);
+        assert!(!report.datasets.contains_key("tank"));
+    }
+
+    #[test]
+    fn report_skipped_and_failed_snapshots() {
+        let pool = pool().deny("tank/www");
+        let report = run(
+            &zfs(&pool, false, &["hourly", "daily"]),
+            &config("keep = 0"),
+        );
+        assert!(!report.success());
+        let www = &report.datasets["tank/www"];
+        assert_eq!(www.snapshots[0].status, Status::Failed);
+        assert_eq!(www.errors[0].operation, "snapshot");
+        assert_eq!(www.snapshots[1].status, Status::Failed);
+        let db = &report.datasets["tank/db"];
+        assert_eq!(db.snapshots[0].status, Status::Created);
+        let pool = pool.snapped("tank/db", "daily", 0, 0);
+        let report = run(&zfs(&pool, false, &["daily"]), &config("min_size = \"1M\""));
+        assert_eq!(
+            report.datasets["tank/db"].snapshots[0].reason.as_deref(),
+            Some("0.0 B written, below min_size 1.0 MiB")
+        );
+    }
+
+    #[test]
+    fn create_by_label_specific_option() {
+        let pool = pool();
+        assert!(run(&zfs(&pool, false, &["daily"]), &config("")).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot)[..2],
+            [
+                "tank/db@zfs-snappers_daily-2022-07-12-1200",
+                "tank/www@zfs-snappers_daily-2022-07-12-1200"
+            ]
+        );
+        assert_eq!(pool.names(FsType::Snapshot).len(), 5);
+    }
+
+    #[test]
+    fn skip_below_min_size() {
+        let pool = pool();
+        let before = pool.names(FsType::Snapshot);
+        assert!(run(
+            &zfs(&pool, false, &["hourly"]),
+            &config("keep = 1\nmin_size = 1024"),
+        )
+        .success());
+        assert_eq!(pool.names(FsType::Snapshot), before);
+        assert!(run(
+            &zfs(&pool, false, &["hourly"]),
+            &config("keep = 3\nmin_size = \"4K\""),
+        )
+        .success());
+        assert_eq!(pool.names(FsType::Snapshot).len(), 4);
+    }
+
+    #[test]
+    fn compare_min_size_with_written_since_the_snapshot_of_the_label() {
+        let pool = pool().snapped("tank/www", "daily", 12, 0);
+        let config = config("min_size = \"8K\"");
+        let report = run(&zfs(&pool, false, &["daily", "hourly"]), &config);
+        let www = &report.datasets["tank/www"];
+        // 2048 + 2048 + 10 bytes in the hourly snapshots since, 4096 in the dataset.
+        assert_eq!(www.snapshots[0].status, Status::Created);
+        assert_eq!(www.snapshots[0].label, "daily");
+        assert_eq!(
+            www.snapshots[1].reason.as_deref(),
+            Some("4.0 KiB written, below min_size 8.0 KiB")
+        );
+    }
+
+    #[test]
+    fn ask_for_written_bytes_once_per_dataset() {
+        let pool = pool()
+            .snapped("tank/www", "daily", 12, 0)
+            .snapped("tank/www", "weekly", 36, 0);
+        let labels = ["daily", "hourly", "weekly"];
+        assert!(run(&zfs(&pool, false, &labels), &config("min_size = \"1M\"")).success());
+        assert_eq!(pool.gets(), 1);
+    }
+
+    #[test]
+    fn change_nothing_in_dry_run() {
+        let pool = pool();
+        let before = pool.names(FsType::Snapshot);
+        assert!(run(&zfs(&pool, true, &["hourly", "daily"]), &config("keep = 0")).success());
+        assert_eq!(pool.names(FsType::Snapshot), before);
+    }
+
+    #[test]
+    fn fail_if_snapshot_is_denied() {
+        let pool = pool().deny("tank/www");
+        let before = pool.names(FsType::Snapshot);
+        let report = run(&zfs(&pool, false, &["hourly"]), &config("keep = 0"));
+        assert!(!report.success());
+        assert!(!report.done("hourly"));
+        // Nothing is pruned without a new snapshot.
+        assert_eq!(pool.names(FsType::Snapshot), before);
+    }
+
+    #[test]
+    fn snapshot_groups_at_once() {
+        let pool = pool();
+        let config = config("[groups.www]\ndatasets = [\"tank/www\", \"tank/db\"]");
+        assert!(run(&zfs(&pool, false, &["daily"]), &config).success());
+        assert_eq!(
+            pool.calls(),
+            ["snapshot tank/db@zfs-snappers_daily-2022-07-12-1200 \
+              tank/www@zfs-snappers_daily-2022-07-12-1200"]
+        );
+    }
+
+    #[test]
+    fn name_snapshots_by_template() {
+        let pool = pool().snapshot(
+            "tank/www@zfs-snappers_hourly-2022-07-12-1200-manual",
+            now(),
+            0,
+        );
+        let config = config("template = \"{label}.{seq}\"\nkeep = 1");
+        for _ in 0..2 {
+            let zfs = Zfs::from_config(&config, false, vec!["hourly".into()], now())
+                .with_backend(pool.clone());
+            assert!(run(&zfs, &config).success());
+        }
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@hourly.1",
+                "tank/www@hourly.2",
+                "tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200-manual",
+            ]
+        );
+    }
+
+    #[test]
+    fn adopt_snapshots_of_other_tools() {
+        let pool = pool()
+            .snapshot(
+                "tank/www@autosnap_2022-07-12_07:00:01_hourly",
+                now() - Duration::hours(5),
+                0,
+            )
+            .snapshot(
+                "tank/www@zfs-auto-snap_hourly-2022-07-12-0800",
+                now() - Duration::hours(4),
+                0,
+            )
+            .snapshot(
+                "tank/www@zfs-auto-snap_daily-2022-07-12-0000",
+                now() - Duration::hours(12),
+                0,
+            );
+        let config = config("adopt = [\"zfs-auto-snapshot\", \"sanoid\"]\nkeep = 3");
+        let zfs = Zfs::from_config(&config, false, vec!["hourly".into()], now())
+            .with_backend(pool.clone());
+        assert!(run(&zfs, &config).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-auto-snap_daily-2022-07-12-0000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200",
+            ]
+        );
+    }
+
+    #[test]
+    fn thin_out_by_gfs_under_the_policies_of_all_labels() {
+        let pool = pool()
+            .snapped("tank/www", "hourly", 72, 0)
+            .snapped("tank/www", "daily", 36, 0);
+        let config = config("keep = 0\n[gfs]\ndaily = 1\n[labels.hourly]\nkeep_within = \"48h\"");
+        // The daily run keeps the hourly snapshots within 48h.
+        assert!(run(&zfs(&pool, false, &["daily"]), &config).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/db@zfs-snappers_daily-2022-07-12-1200",
+                "tank/www@zfs-snappers_daily-2022-07-11-0000",
+                "tank/www@zfs-snappers_daily-2022-07-12-1200",
+                "tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+            ]
+        );
+    }
+
+    #[test]
+    fn disambiguate_reruns_within_a_minute() {
+        let pool = pool();
+        let config = config("keep = 3");
+        for _ in 0..3 {
+            assert!(run(&zfs(&pool, false, &["hourly"]), &config).success());
+        }
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200.2",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200.3",
+            ]
+        );
+    }
+
+    #[test]
+    fn name_shadow_copy2_reruns_a_second_later() {
+        let pool = fake::www();
+        let config = config("template = \"shadow_copy2\"");
+        for _ in 0..2 {
+            let zfs = Zfs::from_config(&config, false, vec!["hourly".into()], now())
+                .with_backend(pool.clone());
+            assert!(run(&zfs, &config).success());
+        }
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly_GMT-2022.07.12-12.00.00",
+                "tank/www@zfs-snappers_hourly_GMT-2022.07.12-12.00.01",
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshot_whole_subtree_recursively() {
+        let pool = FakePool::new(now())
+            .filesystem("tank", &[])
+            .filesystem("tank/db", &[("com.sun:auto-snapshot", "true")])
+            .filesystem("tank/db/data", &[("com.sun:auto-snapshot", "true")])
+            .filesystem("tank/db/wal", &[("com.sun:auto-snapshot", "true")]);
+        let group = "[groups.db]\ndatasets = [\"tank/db\", \"tank/db/data\", \"tank/db/wal\"]";
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config(group)).success());
+        assert_eq!(
+            pool.calls(),
+            ["snapshot -r tank/db@zfs-snappers_hourly-2022-07-12-1200"]
+        );
+        assert_eq!(pool.names(FsType::Snapshot).len(), 3);
+        // The volume below is no member, `-r` would snap it unmanaged.
+        let pool = pool.volume("tank/db/swap");
+        assert!(run(&zfs(&pool, false, &["daily"]), &config(group)).success());
+        assert_eq!(
+            pool.calls()[1],
+            "snapshot tank/db@zfs-snappers_daily-2022-07-12-1200 \
+             tank/db/data@zfs-snappers_daily-2022-07-12-1200 \
+             tank/db/wal@zfs-snappers_daily-2022-07-12-1200"
+        );
+        assert_eq!(pool.names(FsType::Snapshot).len(), 6);
+    }
+
+    #[test]
+    fn destroy_per_dataset_at_once() {
+        let pool = pool();
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config("keep = 1")).success());
+        assert_eq!(
+            pool.calls()[1..],
+            ["destroy tank/www@zfs-snappers_hourly-2022-07-12-0900,zfs-snappers_hourly-2022-07-12-1000"]
+        );
+    }
+
+    #[test]
+    fn split_long_destroys() {
+        let pool = (1..4000).fold(fake::www(), |pool, h| {
+            pool.snapped("tank/www", "hourly", h, 0)
+        });
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config("keep = 1")).success());
+        let destroys = pool.calls()[1..].to_vec();
+        assert_eq!(destroys.len(), 3);
+        assert!(destroys
+            .iter()
+            .all(|d| d.len() <= "destroy ".len() + 64 * 1024));
+        assert_eq!(pool.names(FsType::Snapshot).len(), 2);
+    }
+
+    #[test]
+    fn destroy_one_by_one_after_failure() {
+        let pool = pool().hold("tank/www@zfs-snappers_hourly-2022-07-12-1000");
+        assert!(!run(&zfs(&pool, false, &["hourly"]), &config("keep = 1")).success());
+        assert_eq!(
+            pool.calls()[2..],
+            [
+                "destroy tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "destroy tank/www@zfs-snappers_hourly-2022-07-12-1000"
+            ]
+        );
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly-2022-07-12-1000",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200"
+            ]
+        );
+    }
+
+    #[test]
+    fn abort_snapshot_by_failing_pre_hook() {
+        let pool = pool();
+        let hooks = "[datasets.\"tank/www\".hooks]\npre_snapshot = \"false\"\n\
+                     post_snapshot = \"test $ZFS_SNAPPERS_STATUS = failed\"";
+        assert!(!run(&zfs(&pool, false, &["hourly"]), &config(hooks)).success());
+        assert!(pool.calls().is_empty());
+        let hooks = format!("{hooks}\non_pre_failure = \"continue\"");
+        assert!(!run(&zfs(&pool, false, &["hourly"]), &config(&hooks)).success());
+        assert_eq!(
+            pool.calls(),
+            ["snapshot tank/www@zfs-snappers_hourly-2022-07-12-1200"]
+        );
+    }
+
+    #[test]
+    fn keep_hook_output_out_of_the_json_report() {
+        // Hooks write to the descriptors of the process, so the test reads the stdout of
+        // itself run in a process of its own.
+        if std::env::var_os("ZFS_SNAPPERS_TEST_REPORT").is_some() {
+            let hooks = "[hooks]\npre_snapshot = \"echo CHECKPOINT\"";
+            println!("<report>");
+            let report = run(&zfs(&pool(), false, &["hourly"]), &config(hooks));
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            println!("</report>");
+            return;
+        }
+        let output = process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "should::keep_hook_output_out_of_the_json_report"])
+            .args(["--nocapture", "--test-threads=1"])
+            .env("ZFS_SNAPPERS_TEST_REPORT", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let json = stdout
+            .split_once("<report>\n")
+            .and_then(|(_, rest)| rest.split_once("</report>"))
+            .map(|(json, _)| json)
+            .unwrap();
+        let report: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            report["datasets"]["tank/www"]["snapshots"][0]["status"],
+            "created"
+        );
+        assert!(String::from_utf8_lossy(&output.stderr).contains("CHECKPOINT"));
+    }
+
+    #[test]
+    fn keep_snapshots_vetoed_by_pre_destroy_hook() {
+        let pool = pool();
+        let hooks = "keep = 1\n[hooks]\n\
+                     pre_destroy = \"test $ZFS_SNAPPERS_SNAPSHOT != \
+                     tank/www@zfs-snappers_hourly-2022-07-12-0900\"\n\
+                     post_prune = \"test $ZFS_SNAPPERS_DESTROYED = \
+                     tank/www@zfs-snappers_hourly-2022-07-12-1000\"";
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config(hooks)).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200"
+            ]
+        );
+    }
+
+    #[test]
+    fn veto_by_global_pre_destroy_hook_beside_hooks_of_the_dataset() {
+        let pool = pool();
+        let hooks = "keep = 1\n[hooks]\n\
+                     pre_destroy = \"test $ZFS_SNAPPERS_SNAPSHOT != \
+                     tank/www@zfs-snappers_hourly-2022-07-12-0900\"\n\
+                     [datasets.\"tank/www\".hooks]\npre_snapshot = \"true\"";
+        assert!(run(&zfs(&pool, false, &["hourly"]), &config(hooks)).success());
+        assert_eq!(
+            pool.names(FsType::Snapshot),
+            [
+                "tank/www@zfs-snappers_hourly-2022-07-12-0900",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1100",
+                "tank/www@zfs-snappers_hourly-2022-07-12-1200"
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_pools_locked_by_another_instance() {
+        let pool = pool();
+        let config = config("[lock]\nscope = \"pool\"\nwait = \"0s\"");
+        let held = Lock::acquire(&config.lock.path("tank"), config.lock.wait).unwrap();
+        assert!(held.is_some());
+        let report = run(&zfs(&pool, false, &["hourly"]), &config);
+        assert!(report.success());
+        assert!(!report.done("hourly"));
+        assert!(pool.calls().is_empty());
+        drop(held);
+        let report = run(&zfs(&pool, false, &["hourly"]), &config);
+        assert!(report.done("hourly"));
+        assert!(!pool.calls().is_empty());
     }
 }
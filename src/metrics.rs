@@ -0,0 +1,322 @@
+//! metrics
+//! Writes the state of the managed snapshots as Prometheus metrics, for the textfile
+//! collector of node_exporter.
+use crate::config::Config;
+use crate::lock::Lock;
+use crate::report::{Report, Status};
+use crate::zfs::{Zfs, FS};
+use log::info;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Where the metrics are written to.
+///
+/// ```toml
+/// [metrics]
+/// path = "/var/lib/node_exporter/textfile_collector/zfs-snappers.prom"
+/// ```
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub path: PathBuf,
+}
+
+/// Metrics of the managed snapshots, without the counters.
+const GAUGES: [(&str, &str); 3] = [
+    (
+        "zfs_snappers_snapshots",
+        "Number of managed snapshots per dataset and label.",
+    ),
+    (
+        "zfs_snappers_newest_snapshot_timestamp_seconds",
+        "Creation time of the newest snapshot per dataset and label.",
+    ),
+    (
+        "zfs_snappers_snapshots_used_bytes",
+        "Bytes used by the managed snapshots per dataset and label.",
+    ),
+];
+
+/// Counters, summed up over all runs.
+const COUNTERS: [(&str, &str); 3] = [
+    (
+        "zfs_snappers_snapshots_created_total",
+        "Snapshots created per dataset and label.",
+    ),
+    (
+        "zfs_snappers_snapshots_destroyed_total",
+        "Snapshots destroyed per dataset.",
+    ),
+    (
+        "zfs_snappers_failures_total",
+        "Failed operations, like snapshot, destroy or pre_snapshot.",
+    ),
+];
+
+/// Metrics of the last run.
+const LAST_RUN: [(&str, &str); 3] = [
+    (
+        "zfs_snappers_last_run_timestamp_seconds",
+        "Start of the last run.",
+    ),
+    (
+        "zfs_snappers_last_run_duration_seconds",
+        "Duration of the last run.",
+    ),
+    (
+        "zfs_snappers_last_run_success",
+        "Whether nothing failed in the last run.",
+    ),
+];
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// A series like `name{dataset="tank/www",label="hourly"}`.
+fn series(name: &str, labels: &[(&str, &str)]) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{k}=\"{}\"", escape(v)))
+        .collect();
+    format!("{name}{{{}}}", labels.join(","))
+}
+
+/// Reads the counters of the previous runs from `path`, a missing file counts from zero.
+fn read_counters(path: &Path) -> BTreeMap<String, u64> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter(|line| COUNTERS.iter().any(|(name, _)| line.starts_with(name)))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            Some((series.to_owned(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Reads the gauges of the managed snapshots of the previous run from `path`.
+fn read_gauges(path: &Path) -> [Vec<String>; 3] {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    GAUGES.map(|(name, _)| {
+        content
+            .lines()
+            .filter(|line| line.starts_with(&format!("{name}{{")))
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Adds the snapshots created and destroyed and the failures of `report` to `counters`.
+fn count(report: &Report, counters: &mut BTreeMap<String, u64>) {
+    let mut add = |series: String, n: usize| *counters.entry(series).or_default() += n as u64;
+    for error in &report.errors {
+        add(series(COUNTERS[2].0, &[("operation", &error.operation)]), 1);
+    }
+    for (dataset, ds) in &report.datasets {
+        for sn in ds
+            .snapshots
+            .iter()
+            .filter(|sn| sn.status == Status::Created)
+        {
+            let labels = [("dataset", dataset.as_str()), ("label", &sn.label)];
+            add(series(COUNTERS[0].0, &labels), 1);
+        }
+        if !ds.destroyed.is_empty() {
+            add(
+                series(COUNTERS[1].0, &[("dataset", dataset)]),
+                ds.destroyed.len(),
+            );
+        }
+        for error in &ds.errors {
+            add(series(COUNTERS[2].0, &[("operation", &error.operation)]), 1);
+        }
+    }
+}
+
+/// Writes the metrics of the managed snapshots and of the run of `report` to `path`, by
+/// renaming a file written next to it. Without the `listed` filesystems and snapshots the
+/// gauges of the previous run are kept. Nothing is written when pretending.
+pub fn write(
+    zfs: &Zfs,
+    config: &Config,
+    path: &Path,
+    report: &Report,
+    listed: Option<(&[FS], &[FS])>,
+) -> Result<(), String> {
+    if zfs.pretend() {
+        info!("metrics: not writing {} in dry-run", path.display());
+        return Ok(());
+    }
+    // Every run shares the counters, also those not holding the lock of their pools.
+    let lock = config.lock.dir.join("zfs-snappers-metrics.lock");
+    let _lock = match Lock::acquire(&lock, config.lock.wait) {
+        Ok(Some(lock)) => lock,
+        Ok(None) => return Err(format!("{} is held by another instance", lock.display())),
+        Err(e) => return Err(format!("lock {}: {}", lock.display(), e)),
+    };
+    let (filesystems, snapshots, mut gauges): (&[FS], &[FS], _) = match listed {
+        Some((filesystems, snapshots)) => (filesystems, snapshots, Default::default()),
+        None => (&[], &[], read_gauges(path)),
+    };
+    let labels: BTreeSet<&str> = zfs
+        .labels()
+        .iter()
+        .chain(config.labels.keys())
+        .map(String::as_str)
+        .collect();
+    for fs in filesystems {
+        let ds = report.datasets.get(&fs.name);
+        for &label in &labels {
+            let snaps: Vec<&FS> = zfs
+                .filter_snaps(Some(label), fs, snapshots)
+                .into_iter()
+                .filter(|sn| !ds.is_some_and(|ds| ds.destroyed.contains(&sn.name)))
+                .collect();
+            let created = ds.map_or(0, |ds| {
+                ds.snapshots
+                    .iter()
+                    .filter(|sn| sn.status == Status::Created && sn.label == label)
+                    .count()
+            });
+            if snaps.is_empty() && created == 0 && !fs.snap_for(label) {
+                continue;
+            }
+            let labels = [("dataset", fs.name.as_str()), ("label", label)];
+            let count = snaps.len() + created;
+            gauges[0].push(format!("{} {}", series(GAUGES[0].0, &labels), count));
+            let newest = match created {
+                0 => snaps.last().map(|sn| sn.date()),
+                _ => Some(zfs.now()),
+            };
+            if let Some(newest) = newest {
+                let timestamp = newest.timestamp();
+                gauges[1].push(format!("{} {}", series(GAUGES[1].0, &labels), timestamp));
+            }
+            // New snapshots use nothing yet.
+            let used: u64 = snaps.iter().map(|sn| sn.used()).sum();
+            gauges[2].push(format!("{} {}", series(GAUGES[2].0, &labels), used));
+        }
+    }
+    let mut counters = read_counters(path);
+    count(report, &mut counters);
+
+    let mut content = String::new();
+    let mut metric = |(name, help): (&str, &str), kind: &str, lines: Vec<String>| {
+        let _ = writeln!(content, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for line in lines {
+            let _ = writeln!(content, "{line}");
+        }
+    };
+    for (gauge, lines) in GAUGES.into_iter().zip(gauges) {
+        metric(gauge, "gauge", lines);
+    }
+    for counter in COUNTERS {
+        let lines = counters
+            .iter()
+            .filter(|(series, _)| series.starts_with(&format!("{}{{", counter.0)))
+            .map(|(series, n)| format!("{series} {n}"))
+            .collect();
+        metric(counter, "counter", lines);
+    }
+    let last_run = [
+        zfs.now().timestamp().to_string(),
+        format!("{:.3}", report.seconds),
+        u8::from(report.success()).to_string(),
+    ];
+    for (gauge, value) in LAST_RUN.into_iter().zip(last_run) {
+        metric(gauge, "gauge", vec![format!("{} {value}", gauge.0)]);
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", process::id()));
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, content)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|e| {
+            let _ = fs::remove_file(&tmp);
+            format!("metrics {}: {}", path.display(), e)
+        })
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::fake;
+    use chrono::Duration;
+
+    #[test]
+    fn write_gauges_and_sum_up_counters() {
+        let now = fake::now();
+        let pool = fake::www().snapped("tank/www", "hourly", 1, 2048);
+        let path = std::env::temp_dir().join(format!("zfs-snappers-{}.prom", process::id()));
+        let mut config = Config::parse("keep = 1").ok().unwrap();
+        config.lock.dir = fake::lock_dir();
+        config.metrics = Some(MetricsConfig { path: path.clone() });
+        let labels = vec![String::from("hourly")];
+        let zfs = Zfs::new(false, "zfs-snappers", labels.clone(), now).with_backend(pool.clone());
+        assert!(crate::run(&zfs, &config).success());
+        let later = now + Duration::hours(1);
+        let zfs = Zfs::new(false, "zfs-snappers", labels, later).with_backend(pool);
+        assert!(crate::run(&zfs, &config).success());
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines[..5],
+            [
+                "zfs_snappers_snapshots{dataset=\"tank/www\",label=\"hourly\"} 2",
+                "zfs_snappers_newest_snapshot_timestamp_seconds{dataset=\"tank/www\",label=\"hourly\"} 1657630800",
+                "zfs_snappers_snapshots_used_bytes{dataset=\"tank/www\",label=\"hourly\"} 4096",
+                "zfs_snappers_snapshots_created_total{dataset=\"tank/www\",label=\"hourly\"} 2",
+                "zfs_snappers_snapshots_destroyed_total{dataset=\"tank/www\"} 1",
+            ]
+        );
+        assert!(content.contains("# TYPE zfs_snappers_failures_total counter\n"));
+        assert!(content.ends_with("zfs_snappers_last_run_success 1\n"));
+    }
+
+    #[test]
+    fn write_under_the_metrics_lock_while_the_run_lock_is_held() {
+        let pool = fake::www().snapped("tank/www", "hourly", 1, 2048);
+        let path = std::env::temp_dir().join(format!("zfs-snappers-held-{}.prom", process::id()));
+        let mut config = Config::parse("keep = 1\n[lock]\nwait = \"0s\"")
+            .ok()
+            .unwrap();
+        config.lock.dir = fake::lock_dir();
+        config.metrics = Some(MetricsConfig { path: path.clone() });
+        let zfs =
+            Zfs::new(false, "zfs-snappers", vec!["hourly".into()], fake::now()).with_backend(pool);
+        let gauge = "zfs_snappers_snapshots{dataset=\"tank/www\",label=\"hourly\"} 1";
+
+        // Another instance holds the lock of the run.
+        let held = Lock::acquire(&config.lock.path("tank"), config.lock.wait).unwrap();
+        assert!(held.is_some());
+        assert!(crate::run(&zfs, &config).success());
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(content.contains(gauge));
+        assert!(!content.contains("zfs_snappers_snapshots_created_total{"));
+
+        // Without a listing the gauges are kept.
+        let report = Report::new(String::new(), false).finish();
+        assert!(write(&zfs, &config, &path, &report, None).is_ok());
+        assert!(fs::read_to_string(&path).unwrap().contains(gauge));
+
+        // Another instance writes the metrics.
+        let metrics = config.lock.dir.join("zfs-snappers-metrics.lock");
+        let held = Lock::acquire(&metrics, config.lock.wait).unwrap();
+        assert!(held.is_some());
+        let error = write(&zfs, &config, &path, &report, None).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(error.ends_with("zfs-snappers-metrics.lock is held by another instance"));
+    }
+}
@@ -0,0 +1,493 @@
+//! name
+//! Names of snapshots, rendered from a template and parsed back by a matching pattern.
+use crate::zone::Zone;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Template of the names, if none is configured.
+pub const DEFAULT_TEMPLATE: &str = "{prefix}_{label}-%Y-%m-%d-%H%M";
+
+/// Names Samba's vfs_shadow_copy2 parses to offer the snapshots as "Previous Versions".
+pub const SHADOW_COPY2_TEMPLATE: &str = "{prefix}_{label}_GMT-%Y.%m.%d-%H.%M.%S";
+
+/// Templates known by name.
+const PRESETS: [(&str, &str); 2] = [
+    ("default", DEFAULT_TEMPLATE),
+    ("shadow_copy2", SHADOW_COPY2_TEMPLATE),
+];
+
+/// Naming schemes of other tools, whose snapshots can be adopted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scheme {
+    /// zfs-auto-snapshot, like 'zfs-auto-snap_hourly-2022-07-12-1200'.
+    ZfsAutoSnapshot,
+    /// sanoid, like 'autosnap_2022-07-12_12:00:01_hourly'.
+    Sanoid,
+}
+
+impl Scheme {
+    /// Template recognising the names of the scheme.
+    pub fn template(&self) -> Template {
+        match self {
+            Scheme::ZfsAutoSnapshot => "zfs-auto-snap_{label}-%Y-%m-%d-%H%M",
+            Scheme::Sanoid => "autosnap_%Y-%m-%d_%H:%M:%S_{label}",
+        }
+        .parse()
+        .unwrap()
+    }
+}
+
+/// Characters zfs allows in snapshot-names.
+fn valid_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "-_.:".contains(c)
+}
+
+/// Regular expression of the text of a strftime conversion, `None` if not supported.
+fn conversion(spec: char) -> Option<&'static str> {
+    match spec {
+        'Y' => Some(r"\d{4}"),
+        'y' | 'm' | 'd' | 'H' | 'M' | 'S' => Some(r"\d{2}"),
+        'j' => Some(r"\d{3}"),
+        's' => Some(r"\d+"),
+        'F' => Some(r"\d{4}-\d{2}-\d{2}"),
+        'T' => Some(r"\d{2}:\d{2}:\d{2}"),
+        'R' => Some(r"\d{2}:\d{2}"),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Prefix,
+    Label,
+    Hostname,
+    Seq,
+    Zone,
+    /// strftime-format of the timestamp, with the text between its conversions.
+    Time(String),
+}
+
+/// Template of snapshot-names like '{prefix}_{label}-%Y-%m-%d-%H%M', the part after the '@'.
+/// Besides the strftime-conversions of the timestamp (%Y, %y, %m, %d, %j, %H, %M, %S, %s, %F,
+/// %T, %R) it knows the placeholders `{prefix}`, `{label}`, `{hostname}`, `{seq}`, which
+/// counts the snapshots of a dataset and label, and `{zone}`, the time zone of the timestamp.
+/// The presets 'default' and 'shadow_copy2' name the templates `DEFAULT_TEMPLATE` and
+/// `SHADOW_COPY2_TEMPLATE`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+/// Moves `text` into `parts`, the span from its first to its last conversion as timestamp.
+fn flush(text: &mut String, parts: &mut Vec<Part>) {
+    if let (Some(first), Some(last)) = (text.find('%'), text.rfind('%')) {
+        let (before, rest) = text.split_at(first);
+        let (time, after) = rest.split_at(last - first + 2);
+        for part in [
+            Part::Text(before.into()),
+            Part::Time(time.into()),
+            Part::Text(after.into()),
+        ] {
+            parts.push(part);
+        }
+    } else {
+        parts.push(Part::Text(text.clone()));
+    }
+    parts.retain(|p| p != &Part::Text(String::new()));
+    text.clear();
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == s)
+            .map_or(s, |(_, template)| template);
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            rest = &rest[c.len_utf8()..];
+            match c {
+                '{' => {
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed placeholder in template '{s}'"))?;
+                    flush(&mut text, &mut parts);
+                    parts.push(match &rest[..end] {
+                        "prefix" => Part::Prefix,
+                        "label" => Part::Label,
+                        "hostname" => Part::Hostname,
+                        "seq" => Part::Seq,
+                        "zone" => Part::Zone,
+                        other => {
+                            return Err(format!(
+                                "unknown placeholder '{{{other}}}' in template '{s}'"
+                            ))
+                        }
+                    });
+                    rest = &rest[end + 1..];
+                }
+                '%' => match rest.chars().next() {
+                    Some(spec) if conversion(spec).is_some() => {
+                        text.push(c);
+                        text.push(spec);
+                        rest = &rest[1..];
+                    }
+                    _ => return Err(format!("unsupported conversion in template '{s}'")),
+                },
+                c if valid_char(c) => text.push(c),
+                c => return Err(format!("invalid character '{c}' in template '{s}'")),
+            }
+        }
+        flush(&mut text, &mut parts);
+        let count = |f: fn(&Part) -> bool| parts.iter().filter(|p| f(p)).count();
+        if count(|p| p == &Part::Label) != 1 {
+            return Err(format!("template '{s}' needs {{label}} once"));
+        }
+        let times = count(|p| matches!(p, Part::Time(_)));
+        if times > 1 {
+            return Err(format!(
+                "placeholders must not interrupt the timestamp in template '{s}'"
+            ));
+        }
+        if times == 0 && count(|p| p == &Part::Seq) == 0 {
+            return Err(format!("template '{s}' needs a timestamp or {{seq}}"));
+        }
+        let template = Self {
+            source: s.to_owned(),
+            parts,
+        };
+        // Names have to be recognised later.
+        let sample = Utc.ymd(2022, 7, 12).and_hms(12, 34, 56);
+        let pattern = template.pattern("prefix", "host", None);
+        if pattern
+            .parse(&template.render("prefix", "label", "host", &sample, Zone::Utc, 1))
+            .is_none()
+        {
+            return Err(format!("the timestamp of template '{s}' is not parseable"));
+        }
+        Ok(template)
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Template {
+    /// Whether this is `DEFAULT_TEMPLATE`.
+    pub fn is_default(&self) -> bool {
+        self.source == DEFAULT_TEMPLATE
+    }
+
+    /// Whether this is `SHADOW_COPY2_TEMPLATE`, whose names allow no suffix.
+    pub fn is_shadow_copy2(&self) -> bool {
+        self.source == SHADOW_COPY2_TEMPLATE
+    }
+
+    /// Whether the names count the snapshots by `{seq}`.
+    pub fn has_seq(&self) -> bool {
+        self.parts.contains(&Part::Seq)
+    }
+
+    /// Whether the names are marked by the time zone.
+    pub fn has_zone(&self) -> bool {
+        self.parts.contains(&Part::Zone)
+    }
+
+    /// Name of a new snapshot, the part after the '@', with the timestamp in `zone`.
+    pub fn render(
+        &self,
+        prefix: &str,
+        label: &str,
+        hostname: &str,
+        time: &DateTime<Utc>,
+        zone: Zone,
+        seq: u32,
+    ) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Prefix => prefix.to_owned(),
+                Part::Label => label.to_owned(),
+                Part::Hostname => hostname.to_owned(),
+                Part::Seq => seq.to_string(),
+                Part::Zone => zone.marker(time),
+                Part::Time(format) => zone.at(time).format(format).to_string(),
+            })
+            .collect()
+    }
+
+    /// `name` made unique by the suffix `.{n}`, when a snapshot of that name already exists.
+    /// The patterns recognise the suffix.
+    pub fn disambiguate(name: &str, n: u32) -> String {
+        format!("{name}.{n}")
+    }
+
+    /// Pattern recognising the names of the snapshots of `label`, or of any label if `None`.
+    pub fn pattern(&self, prefix: &str, hostname: &str, label: Option<&str>) -> Pattern {
+        let mut time = None;
+        let regex: String = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => regex::escape(text),
+                Part::Prefix => regex::escape(prefix),
+                Part::Label => match label {
+                    Some(label) => format!("(?P<label>{})", regex::escape(label)),
+                    // Lazy, leaving the suffix of `disambiguate` to a template ending in
+                    // the label.
+                    None => String::from("(?P<label>[A-Za-z0-9_.:-]+?)"),
+                },
+                Part::Hostname => regex::escape(hostname),
+                Part::Seq => String::from(r"(?P<seq>\d+)"),
+                Part::Zone => String::from("(?P<zone>[A-Za-z0-9]+)"),
+                Part::Time(format) => {
+                    time = Some(format.clone());
+                    let mut regex = String::new();
+                    let mut chars = format.chars();
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '%' => regex.push_str(chars.next().and_then(conversion).unwrap_or("")),
+                            c => regex.push_str(&regex::escape(&c.to_string())),
+                        }
+                    }
+                    format!("(?P<time>{regex})")
+                }
+            })
+            .collect();
+        Pattern {
+            regex: Regex::new(&format!(r"^{regex}(?:\.(?P<dup>\d+))?$")).unwrap(),
+            time,
+        }
+    }
+}
+
+/// Pattern recognising the names of releases before templates, `{prefix}_{label}-` followed by
+/// any timestamp. The time of their snapshots is the creation.
+pub fn legacy_pattern(prefix: &str, label: Option<&str>) -> Pattern {
+    let label = match label {
+        Some(label) => regex::escape(label),
+        None => String::from("[A-Za-z0-9_.:]+?"),
+    };
+    Pattern {
+        regex: Regex::new(&format!(
+            r"^{}_(?P<label>{label})-[A-Za-z0-9_.:-]+$",
+            regex::escape(prefix)
+        ))
+        .unwrap(),
+        time: None,
+    }
+}
+
+/// What the name of a snapshot tells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parsed {
+    pub label: String,
+    /// Timestamp in the zone of the names.
+    pub time: Option<NaiveDateTime>,
+    pub seq: Option<u32>,
+    pub zone: Option<String>,
+    /// Suffix of a name which was rendered twice, see `Template::disambiguate`.
+    pub dup: Option<u32>,
+}
+
+/// Recognises the names rendered by a template.
+#[derive(Debug)]
+pub struct Pattern {
+    regex: Regex,
+    /// strftime-format of the timestamp.
+    time: Option<String>,
+}
+
+impl Pattern {
+    /// Parses the name of a snapshot, the part after the '@' (or '#' of a bookmark). Returns
+    /// `None` if the name was not rendered by the template.
+    pub fn parse(&self, name: &str) -> Option<Parsed> {
+        let caps = self.regex.captures(name)?;
+        let time = match (&self.time, caps.name("time")) {
+            (Some(format), Some(time)) => {
+                let time = time.as_str();
+                // An impossible timestamp, like month 13, is not ours.
+                let parsed = NaiveDateTime::parse_from_str(time, format)
+                    .ok()
+                    .or_else(|| {
+                        NaiveDate::parse_from_str(time, format)
+                            .ok()
+                            .map(|d| d.and_hms(0, 0, 0))
+                    })?;
+                Some(parsed)
+            }
+            _ => None,
+        };
+        Some(Parsed {
+            label: caps["label"].to_owned(),
+            time,
+            seq: caps.name("seq").and_then(|s| s.as_str().parse().ok()),
+            zone: caps.name("zone").map(|z| z.as_str().to_owned()),
+            dup: caps.name("dup").and_then(|d| d.as_str().parse().ok()),
+        })
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.parse(name).is_some()
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn render_and_parse_names() {
+        let time = Utc.ymd(2022, 7, 12).and_hms(12, 34, 56);
+        let template = Template::default();
+        let name = template.render("zfs-snappers", "hourly", "host", &time, Zone::Utc, 1);
+        assert_eq!(name, "zfs-snappers_hourly-2022-07-12-1234");
+        let pattern = template.pattern("zfs-snappers", "host", None);
+        assert_eq!(
+            pattern.parse(&name),
+            Some(Parsed {
+                label: String::from("hourly"),
+                time: Some(NaiveDate::from_ymd(2022, 7, 12).and_hms(12, 34, 0)),
+                seq: None,
+                zone: None,
+                dup: None,
+            })
+        );
+        let again = Template::disambiguate(&name, 2);
+        assert_eq!(again, "zfs-snappers_hourly-2022-07-12-1234.2");
+        assert_eq!(pattern.parse(&again).and_then(|p| p.dup), Some(2));
+        let hourly = template.pattern("zfs-snappers", "host", Some("hourly"));
+        assert!(hourly.is_match(&name));
+        assert!(!hourly.is_match("zfs-snappers_hourly-2022-13-12-1234"));
+        assert!(!hourly.is_match("zfs-snappers_daily-2022-07-12-1234"));
+        assert!(!pattern.is_match("zfs-snappers_hourly-2022-07-12-1234-x"));
+
+        let template: Template = "{hostname}.{label}.{seq}.%F".parse().unwrap();
+        let name = template.render("zfs-snappers", "daily", "nas", &time, Zone::Utc, 7);
+        assert_eq!(name, "nas.daily.7.2022-07-12");
+        let parsed = template.pattern("zfs-snappers", "nas", None).parse(&name);
+        assert_eq!(parsed.and_then(|p| p.seq), Some(7));
+        assert!(!template
+            .pattern("zfs-snappers", "other", None)
+            .is_match(&name));
+
+        let template: Template = "%F-%H%M_{label}".parse().unwrap();
+        let name = template.render("zfs-snappers", "hourly", "host", &time, Zone::Utc, 1);
+        let again = Template::disambiguate(&name, 2);
+        assert_eq!(again, "2022-07-12-1234_hourly.2");
+        let parsed = template.pattern("zfs-snappers", "host", None).parse(&again);
+        assert_eq!(
+            parsed.map(|p| (p.label, p.dup)),
+            Some((String::from("hourly"), Some(2)))
+        );
+    }
+
+    #[test]
+    fn recognise_names_of_previous_releases() {
+        let legacy = legacy_pattern("zfs-snapshot", None);
+        let parsed = legacy.parse("zfs-snapshot_weekly-2019-12-30_1207").unwrap();
+        assert_eq!(parsed.label, "weekly");
+        assert_eq!(parsed.time, None);
+        let weekly = legacy_pattern("zfs-snapshot", Some("weekly"));
+        assert!(weekly.is_match("zfs-snapshot_weekly-2019-12-30_1207"));
+        assert!(!weekly.is_match("zfs-snapshot_daily-2019-12-30_1207"));
+        assert!(!weekly.is_match("zfs-snapshot_weekly"));
+        assert!(!weekly.is_match("other_weekly-2019-12-30_1207"));
+        assert!(Template::default().is_default());
+        assert!(!"shadow_copy2".parse::<Template>().unwrap().is_default());
+    }
+
+    #[test]
+    fn render_in_zone() {
+        let time = Utc.ymd(2022, 10, 30).and_hms(0, 30, 0);
+        let template: Template = "{label}-%F-%H%M-{zone}".parse().unwrap();
+        let berlin: Zone = "Europe/Berlin".parse().unwrap();
+        let names: Vec<String> = [time, time + chrono::Duration::hours(1)]
+            .iter()
+            .map(|t| template.render("zfs-snappers", "hourly", "host", t, berlin, 1))
+            .collect();
+        assert_eq!(
+            names,
+            ["hourly-2022-10-30-0230-CEST", "hourly-2022-10-30-0230-CET"]
+        );
+        let pattern = template.pattern("zfs-snappers", "host", Some("hourly"));
+        let parsed = pattern.parse(&names[1]).unwrap();
+        assert_eq!(parsed.zone.as_deref(), Some("CET"));
+    }
+
+    #[test]
+    fn expand_presets() {
+        let time = Utc.ymd(2022, 7, 12).and_hms(12, 34, 56);
+        let template: Template = "shadow_copy2".parse().unwrap();
+        assert_eq!(template.to_string(), SHADOW_COPY2_TEMPLATE);
+        assert_eq!(
+            template.render("zfs-snappers", "hourly", "host", &time, Zone::Utc, 1),
+            "zfs-snappers_hourly_GMT-2022.07.12-12.34.56"
+        );
+        assert_eq!("default".parse(), Ok(Template::default()));
+        assert!(template.is_shadow_copy2());
+        assert!(!Template::default().is_shadow_copy2());
+    }
+
+    #[test]
+    fn recognise_foreign_schemes() {
+        let hourly = |scheme: Scheme| {
+            scheme
+                .template()
+                .pattern("zfs-snappers", "host", Some("hourly"))
+        };
+        let auto = hourly(Scheme::ZfsAutoSnapshot);
+        assert!(auto.is_match("zfs-auto-snap_hourly-2022-07-12-1200"));
+        assert!(!auto.is_match("zfs-auto-snap_daily-2022-07-12-1200"));
+        let sanoid = hourly(Scheme::Sanoid);
+        let parsed = sanoid.parse("autosnap_2022-07-12_12:00:01_hourly").unwrap();
+        assert_eq!(
+            parsed.time,
+            Some(NaiveDate::from_ymd(2022, 7, 12).and_hms(12, 0, 1))
+        );
+        assert!(!sanoid.is_match("autosnap_2022-07-12_12:00:01_daily"));
+    }
+
+    #[test]
+    fn reject_invalid_templates() {
+        assert!("{prefix}-%Y%m%d".parse::<Template>().is_err());
+        assert!("{label}-{label}-%Y".parse::<Template>().is_err());
+        assert!("{label}".parse::<Template>().is_err());
+        assert!("{label}-{nope}-%F".parse::<Template>().is_err());
+        assert!("{label}-%Y-{seq}-%m".parse::<Template>().is_err());
+        assert!("{label} %F".parse::<Template>().is_err());
+        assert!("{label}-%Y".parse::<Template>().is_err());
+        assert!("{label}-%a".parse::<Template>().is_err());
+        assert!("{label}-{seq}".parse::<Template>().is_ok());
+    }
+}
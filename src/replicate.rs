@@ -0,0 +1,709 @@
+//! replicate
+//! Sends the newest snapshots to a target dataset, locally or through a command like ssh.
+//! Interrupted transfers are resumed by the `receive_resume_token` of the replica, bookmarks
+//! keep the incremental base when its snapshot is pruned.
+use crate::backend::{Cli, ZfsBackend};
+use crate::config::ReplicationConfig;
+use crate::zfs::{default_exec, FsType, Zfs, ZfsError, FS};
+use log::{debug, error, info, warn};
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Interval of progress-messages while sending.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What to send to bring a replica up to date.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Plan<'a> {
+    Nothing,
+    Full(&'a FS),
+    Incremental { base: &'a FS, snap: &'a FS },
+}
+
+/// A dataset on the receiving side.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct Replica {
+    /// guids of its snapshots.
+    pub guids: HashSet<u64>,
+    /// Left by an interrupted `zfs receive -s`.
+    pub resume_token: Option<String>,
+}
+
+/// Decides what to send of `source`, sorted by date, to a replica with snapshots of `replica`.
+pub fn plan<'a>(
+    source: &[&'a FS],
+    bookmarks: &[&'a FS],
+    replica: Option<&HashSet<u64>>,
+) -> Result<Plan<'a>, ZfsError> {
+    let snap = match source.last() {
+        Some(&snap) => snap,
+        None => return Ok(Plan::Nothing),
+    };
+    // A replica without snapshots is a parent created for the replicas below.
+    let guids = match replica {
+        Some(guids) if !guids.is_empty() => guids,
+        _ => return Ok(Plan::Full(snap)),
+    };
+    if guids.contains(&snap.guid()) {
+        return Ok(Plan::Nothing);
+    }
+    // The most recent common base, a snapshot wins over its bookmark.
+    let base = source
+        .iter()
+        .chain(bookmarks)
+        .filter(|sn| guids.contains(&sn.guid()))
+        .max_by_key(|sn| (sn.date(), sn.fs_type() == FsType::Snapshot));
+    match base {
+        Some(&base) => Ok(Plan::Incremental { base, snap }),
+        None => Err(ZfsError::InternalError(format!(
+            "replica of {} has no snapshot in common with the source, rename or destroy it \
+             to start over by a full send",
+            snap.dataset()
+        ))),
+    }
+}
+
+/// Sends snapshots of a local `Zfs` to the replication target.
+pub struct Replicator<'a> {
+    zfs: &'a Zfs,
+    target: String,
+    /// zfs-command of the receiving side.
+    command: String,
+    receiver: Box<dyn ZfsBackend>,
+    pretend: bool,
+    /// Listed once, also a failure is not retried.
+    replicas: OnceCell<Result<HashMap<String, Replica>, String>>,
+}
+
+impl<'a> Replicator<'a> {
+    pub fn new(zfs: &'a Zfs, config: &ReplicationConfig) -> Self {
+        let command = config.command.clone().unwrap_or_else(default_exec);
+        Self {
+            zfs,
+            target: config.target.clone(),
+            receiver: Box::new(Cli::command(&command)),
+            command,
+            pretend: zfs.pretend(),
+            replicas: OnceCell::new(),
+        }
+    }
+
+    /// Only logs the changes, also if `zfs` does not pretend.
+    pub fn pretending(mut self) -> Self {
+        self.pretend = true;
+        self
+    }
+
+    /// Name of the replica of `fs`.
+    pub fn target_name(&self, fs: &FS) -> String {
+        format!("{}/{}", self.target, fs.name)
+    }
+
+    /// Lists the datasets below `root` on the receiving side with the guids of their
+    /// snapshots. A missing `root` has no replicas.
+    pub fn list_target(&self, root: &str) -> Result<HashMap<String, Replica>, ZfsError> {
+        debug!("{}: list {}", self.command, root);
+        match self
+            .receiver
+            .list_tree("name,guid,receive_resume_token", root)
+        {
+            Ok(output) => Ok(parse_replicas(&output)),
+            Err(ZfsError::NoSuchDataset(_)) => Ok(HashMap::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The replicas below the target, listed once. If the target is unreachable, the
+    /// error is repeated without contacting it again.
+    pub fn replicas(&self) -> Result<&HashMap<String, Replica>, ZfsError> {
+        self.replicas
+            .get_or_init(|| self.list_target(&self.target).map_err(|e| e.to_string()))
+            .as_ref()
+            .map_err(|e| {
+                ZfsError::InternalError(format!("replication target {}: {e}", self.target))
+            })
+    }
+
+    /// Bookmarks the most recent snapshot of `fs` in common with its replica, if it is about
+    /// to be pruned and not bookmarked by a send yet. If the target is unreachable, all
+    /// `expendable` snapshots are bookmarked, those failing are kept.
+    pub fn protect_base(
+        &self,
+        fs: &FS,
+        snaps: &[FS],
+        bookmarks: &[FS],
+        expendable: &mut Vec<&FS>,
+    ) -> Result<(), ZfsError> {
+        // Every send bookmarks its snapshot, which keeps the base without asking the target.
+        if !self.zfs.managed_snapshots(fs, bookmarks).is_empty() {
+            return Ok(());
+        }
+        let bases: Vec<&FS> = match self.replicas() {
+            Ok(replicas) => match replicas.get(&self.target_name(fs)) {
+                Some(replica) => self
+                    .zfs
+                    .managed_snapshots(fs, snaps)
+                    .into_iter()
+                    .rev()
+                    .find(|sn| replica.guids.contains(&sn.guid()))
+                    .filter(|base| expendable.iter().any(|exp| exp.name == base.name))
+                    .into_iter()
+                    .collect(),
+                None => return Ok(()),
+            },
+            Err(e) => {
+                warn!("{}, bookmarking the expendable snapshots of {}", e, fs.name);
+                expendable.clone()
+            }
+        };
+        let mut result = Ok(());
+        for base in bases {
+            if let Err(e) = self.bookmark(base) {
+                expendable.retain(|exp| exp.name != base.name);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Bookmarks `snap`, unless pretending.
+    fn bookmark(&self, snap: &FS) -> Result<(), ZfsError> {
+        match self.pretend {
+            true => {
+                info!("zfs bookmark {}", snap.name);
+                Ok(())
+            }
+            false => self.zfs.create_bookmark(snap),
+        }
+    }
+
+    /// Sends the snapshots of `fs` since the most recent one in common with the replica.
+    pub fn replicate(&self, fs: &FS, snaps: &[FS], bookmarks: &[FS]) -> Result<(), ZfsError> {
+        let source = self.zfs.managed_snapshots(fs, snaps);
+        let bookmarks = self.zfs.managed_snapshots(fs, bookmarks);
+        let target = self.target_name(fs);
+        let replicas = self.replicas()?;
+        if let Some(token) = replicas.get(&target).and_then(|r| r.resume_token.as_ref()) {
+            info!("resuming interrupted receive into {}", target);
+            self.transfer(&["-t", token], &target, false)?;
+            // The resumed snapshot is part of the replica now.
+            let replicas = self.list_target(&target)?;
+            return self.send_newest(&source, &bookmarks, &target, &replicas);
+        }
+        self.send_newest(&source, &bookmarks, &target, replicas)
+    }
+
+    fn send_newest(
+        &self,
+        source: &[&FS],
+        bookmarks: &[&FS],
+        target: &str,
+        replicas: &HashMap<String, Replica>,
+    ) -> Result<(), ZfsError> {
+        let snap = match plan(source, bookmarks, replicas.get(target).map(|r| &r.guids))? {
+            Plan::Nothing => {
+                debug!("{} is up to date", target);
+                match source.last() {
+                    Some(&snap) => snap,
+                    None => return Ok(()),
+                }
+            }
+            Plan::Full(snap) => {
+                if let Some((parent, _)) = target.rsplit_once('/') {
+                    if !replicas.contains_key(parent) {
+                        info!("{} create -p {}", self.command, parent);
+                        if !self.pretend {
+                            self.receiver.create(parent)?;
+                        }
+                    }
+                }
+                // Overwrites the parent created for a replica below.
+                self.transfer(&[&snap.name], target, replicas.contains_key(target))?;
+                snap
+            }
+            Plan::Incremental { base, snap } => {
+                // A bookmark can not start `-I`, the snapshot after it is sent by `-i` first.
+                let mut base = base;
+                if base.fs_type() == FsType::Bookmark {
+                    if let Some(&next) = source.iter().find(|sn| sn.date() > base.date()) {
+                        self.transfer(&["-i", &base.name, &next.name], target, false)?;
+                        base = next;
+                    }
+                }
+                if base.name != snap.name {
+                    self.transfer(&["-I", &base.name, &snap.name], target, false)?;
+                }
+                snap
+            }
+        };
+        // The sent snapshot is the base of the next send, older bookmarks are obsolete.
+        if !bookmarks.iter().any(|bm| bm.guid() == snap.guid()) {
+            self.bookmark(snap)?;
+        }
+        for bm in bookmarks.iter().filter(|bm| bm.date() < snap.date()) {
+            self.zfs.remove_snapshot(bm)?;
+        }
+        Ok(())
+    }
+
+    /// Pipes `zfs send` into a resumable `zfs receive` on the receiving side, with `force`
+    /// overwriting `target`.
+    fn transfer(&self, source: &[&str], target: &str, force: bool) -> Result<(), ZfsError> {
+        let receive = format!(
+            "{} receive -s -u {}{}",
+            self.command,
+            if force { "-F " } else { "" },
+            target
+        );
+        if self.pretend {
+            info!("zfs send {} | {}", source.join(" "), receive);
+            return Ok(());
+        }
+        let size = self.zfs.send_size(source);
+        let mut send = self.zfs.send(source)?;
+        info!("{}", receive);
+        let mut receive = match self.receiver.receive(target, force) {
+            Ok(receive) => receive,
+            Err(e) => {
+                let _ = send.kill();
+                let _ = send.wait();
+                return Err(e);
+            }
+        };
+        let copied = match (send.stdout.take(), receive.stdin.take()) {
+            (Some(mut stream), Some(mut input)) => copy(&mut stream, &mut input, size, target),
+            _ => Err((io::Error::other("no pipe to zfs send or receive"), 0)),
+        };
+        let received = receive.wait()?;
+        let sent = send.wait()?;
+        let (error, bytes) = match copied {
+            Ok(bytes) if sent.success() && received.success() => {
+                info!("{}: received {}", target, progress(bytes, size));
+                return Ok(());
+            }
+            Ok(bytes) => (None, bytes),
+            Err((e, bytes)) => (Some(e), bytes),
+        };
+        Err(ZfsError::InternalError(format!(
+            "transfer into {} interrupted after {} (send: {}, receive: {}{}), resuming on the next run",
+            target,
+            progress(bytes, size),
+            sent,
+            received,
+            error.map(|e| format!(", {e}")).unwrap_or_default()
+        )))
+    }
+}
+
+/// Parses the output of `zfs list -o name,guid,receive_resume_token`.
+fn parse_replicas(output: &str) -> HashMap<String, Replica> {
+    let mut replicas: HashMap<String, Replica> = HashMap::new();
+    for line in output.lines() {
+        let mut columns = line.split('\t');
+        let name = columns.next().unwrap_or_default();
+        let guid = columns.next().and_then(|g| g.parse().ok());
+        let token = columns.next().filter(|t| !t.is_empty() && *t != "-");
+        match (name.split_once('@'), guid) {
+            (Some((dataset, _)), Some(guid)) => {
+                replicas
+                    .entry(dataset.to_owned())
+                    .or_default()
+                    .guids
+                    .insert(guid);
+            }
+            (Some(_), None) => {}
+            (None, _) => {
+                replicas.entry(name.to_owned()).or_default().resume_token = token.map(String::from);
+            }
+        }
+    }
+    replicas
+}
+
+/// Copies `stream` into `input`, logging the progress. Returns the number of bytes copied,
+/// also if interrupted.
+fn copy(
+    stream: &mut dyn Read,
+    input: &mut dyn Write,
+    size: Option<u64>,
+    target: &str,
+) -> Result<u64, (io::Error, u64)> {
+    let mut buf = vec![0u8; 128 * 1024];
+    let mut bytes = 0u64;
+    let mut reported = Instant::now();
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(0) => return Ok(bytes),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err((e, bytes)),
+        };
+        if let Err(e) = input.write_all(&buf[..n]) {
+            return Err((e, bytes));
+        }
+        bytes += n as u64;
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            info!("{}: {}", target, progress(bytes, size));
+            reported = Instant::now();
+        }
+    }
+}
+
+/// Describes the number of bytes transferred, relative to the estimated `size`.
+fn progress(bytes: u64, size: Option<u64>) -> String {
+    let mib = |b: u64| b as f64 / (1024.0 * 1024.0);
+    match size {
+        Some(size) if size > 0 => format!(
+            "{:.1} of {:.1} MiB ({:.0}%)",
+            mib(bytes),
+            mib(size),
+            100.0 * bytes as f64 / size as f64
+        ),
+        _ => format!("{:.1} MiB", mib(bytes)),
+    }
+}
+
+/// Replicates every managed dataset, returns whether all succeeded.
+pub fn run(zfs: &Zfs, config: &ReplicationConfig) -> bool {
+    let replicator = Replicator::new(zfs, config);
+    if let Err(e) = replicator.replicas() {
+        error!("{}", e);
+        return false;
+    }
+    let listed = zfs
+        .list_filesystems(FsType::Snapshot)
+        .and_then(|snapshots| {
+            let bookmarks = zfs.list_filesystems(FsType::Bookmark)?;
+            let filesystems = zfs.list_filesystems(FsType::Filesystem)?;
+            Ok((snapshots, bookmarks, filesystems))
+        });
+    let (snapshots, bookmarks, filesystems) = match listed {
+        Ok(listed) => listed,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let mut success = true;
+    for fs in filesystems.iter().filter(|f| f.managed()) {
+        if let Err(e) = replicator.replicate(fs, &snapshots, &bookmarks) {
+            error!("{}", e);
+            success = false;
+        }
+    }
+    success
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::fake::{self, FakePool};
+    use chrono::Duration;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process;
+
+    /// A stand-in for the zfs-command of the receiving side in `dir`. It lists the file
+    /// `target` as the replicas, appends the received streams to `received` and fails
+    /// receiving if the file `fail` exists, everything if `down` exists.
+    fn receiver(dir: &Path) -> String {
+        let script = dir.join("zfs");
+        let d = dir.display();
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                 echo \"$*\" >> {d}/calls\n\
+                 case \"$1\" in\n\
+                 list) [ -e {d}/down ] && {{ echo 'ssh: connect to host backup: Connection refused' >&2; exit 255; }}\n  \
+                   cat {d}/target 2>/dev/null || {{ echo \"cannot open 'backup': dataset does not exist\" >&2; exit 1; }} ;;\n\
+                 receive) cat >> {d}/received; echo >> {d}/received\n  \
+                   [ -e {d}/fail ] && {{ echo 'cannot receive: checksum mismatch' >&2; exit 1; }} ;;\n\
+                 esac\n\
+                 exit 0\n"
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script.display().to_string()
+    }
+
+    fn snaps(guids: &[u64]) -> Vec<FS> {
+        let now = fake::now();
+        guids
+            .iter()
+            .enumerate()
+            .map(|(i, guid)| {
+                FS::new(
+                    &format!("tank@zfs-snappers_hourly-{i}"),
+                    FsType::Snapshot,
+                    now + Duration::hours(i as i64),
+                )
+                .with_guid(*guid)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plan_full_incremental_or_nothing() {
+        let all = snaps(&[11, 12, 13]);
+        let source: Vec<&FS> = all.iter().collect();
+        assert_eq!(plan(&[], &[], None).ok(), Some(Plan::Nothing));
+        assert_eq!(plan(&source, &[], None).ok(), Some(Plan::Full(&all[2])));
+        let placeholder = HashSet::new();
+        assert_eq!(
+            plan(&source, &[], Some(&placeholder)).ok(),
+            Some(Plan::Full(&all[2]))
+        );
+        let replica: HashSet<u64> = [11, 12].into_iter().collect();
+        assert_eq!(
+            plan(&source, &[], Some(&replica)).ok(),
+            Some(Plan::Incremental {
+                base: &all[1],
+                snap: &all[2]
+            })
+        );
+        let replica: HashSet<u64> = [13].into_iter().collect();
+        assert_eq!(plan(&source, &[], Some(&replica)).ok(), Some(Plan::Nothing));
+        let replica: HashSet<u64> = [99].into_iter().collect();
+        assert!(plan(&source, &[], Some(&replica)).is_err());
+    }
+
+    #[test]
+    fn plan_incremental_from_bookmark() {
+        let all = snaps(&[11, 12, 13]);
+        let bookmark = FS::new(
+            "tank#zfs-snappers_hourly-1",
+            FsType::Bookmark,
+            all[1].date(),
+        )
+        .with_guid(12);
+        // The snapshot of the bookmark was pruned.
+        let source = [&all[0], &all[2]];
+        let replica: HashSet<u64> = [11, 12].into_iter().collect();
+        assert_eq!(
+            plan(&source, &[&bookmark], Some(&replica)).ok(),
+            Some(Plan::Incremental {
+                base: &bookmark,
+                snap: &all[2]
+            })
+        );
+        // A snapshot wins over its bookmark.
+        let source: Vec<&FS> = all.iter().collect();
+        assert_eq!(
+            plan(&source, &[&bookmark], Some(&replica)).ok(),
+            Some(Plan::Incremental {
+                base: &all[1],
+                snap: &all[2]
+            })
+        );
+    }
+
+    #[test]
+    fn parse_resume_tokens() {
+        let replicas = parse_replicas(
+            "backup\t5\t-\n\
+             backup/tank\t6\t1-e604ea4bf-e0-789c63a2\n\
+             backup/tank@zfs-snappers_hourly-2022-07-12-1200\t111\t-\n",
+        );
+        assert_eq!(replicas["backup"], Replica::default());
+        assert_eq!(
+            replicas["backup/tank"].resume_token.as_deref(),
+            Some("1-e604ea4bf-e0-789c63a2")
+        );
+        assert!(replicas["backup/tank"].guids.contains(&111));
+        assert_eq!(
+            progress(512 * 1024, Some(1024 * 1024)),
+            "0.5 of 1.0 MiB (50%)"
+        );
+    }
+
+    #[test]
+    fn send_full_resume_and_report_interrupted_receives() {
+        let dir = std::env::temp_dir().join(format!("zfs-snappers-receiver-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = fake::now();
+        let pool = fake::www()
+            .snapshot(
+                "tank/www#zfs-snappers_hourly-2022-07-12-0900",
+                now - Duration::hours(3),
+                0,
+            )
+            .snapped("tank/www", "hourly", 2, 0)
+            .snapped("tank/www", "hourly", 1, 0);
+        let zfs =
+            Zfs::new(false, "zfs-snappers", vec!["hourly".into()], now).with_backend(pool.clone());
+        let config = ReplicationConfig {
+            target: String::from("backup"),
+            command: Some(receiver(&dir)),
+        };
+        let read = |file: &str| fs::read_to_string(dir.join(file)).unwrap_or_default();
+
+        // No replica yet.
+        assert!(run(&zfs, &config));
+        assert_eq!(
+            read("received"),
+            "tank/www@zfs-snappers_hourly-2022-07-12-1100\n"
+        );
+        assert!(read("calls").contains("create -p backup/tank\n"));
+        assert!(read("calls").ends_with("receive -s -u backup/tank/www\n"));
+        // The sent snapshot is the base from now on.
+        assert_eq!(
+            pool.names(FsType::Bookmark),
+            ["tank/www#zfs-snappers_hourly-2022-07-12-1100"]
+        );
+
+        // An interrupted receive of the newest snapshot, on top of the older one.
+        let guid = zfs.list_filesystems(FsType::Snapshot).unwrap()[0].guid();
+        fs::write(
+            dir.join("target"),
+            format!(
+                "backup\t1\t-\nbackup/tank\t2\t-\nbackup/tank/www\t3\t1-token\n\
+                 backup/tank/www@zfs-snappers_hourly-2022-07-12-1000\t{guid}\t-\n"
+            ),
+        )
+        .unwrap();
+        fs::remove_file(dir.join("received")).unwrap();
+        assert!(run(&zfs, &config));
+        assert_eq!(
+            read("received"),
+            "-t 1-token\n-I tank/www@zfs-snappers_hourly-2022-07-12-1000 \
+             tank/www@zfs-snappers_hourly-2022-07-12-1100\n"
+        );
+
+        fs::write(dir.join("fail"), "").unwrap();
+        let snapshots = zfs.list_filesystems(FsType::Snapshot).unwrap();
+        let www = &zfs.list_filesystems(FsType::Filesystem).unwrap()[1];
+        let error = Replicator::new(&zfs, &config)
+            .replicate(www, &snapshots, &[])
+            .unwrap_err()
+            .to_string();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.starts_with("transfer into backup/tank/www interrupted after"));
+        assert!(error.ends_with("resuming on the next run"));
+    }
+
+    #[test]
+    fn send_intermediates_into_created_parents() {
+        let dir = std::env::temp_dir().join(format!("zfs-snappers-parents-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = fake::now();
+        let pool = FakePool::new(now)
+            .filesystem("tank", &[("com.sun:auto-snapshot", "true")])
+            .filesystem("tank/www", &[("com.sun:auto-snapshot", "true")])
+            .snapped("tank", "hourly", 1, 0)
+            .snapshot(
+                "tank/www#zfs-snappers_hourly-2022-07-12-0900",
+                now - Duration::hours(3),
+                0,
+            )
+            .snapped("tank/www", "hourly", 2, 0)
+            .snapped("tank/www", "hourly", 1, 0);
+        let zfs = Zfs::new(false, "zfs-snappers", vec!["hourly".into()], now).with_backend(pool);
+        let config = ReplicationConfig {
+            target: String::from("backup"),
+            command: Some(receiver(&dir)),
+        };
+        let read = |file: &str| fs::read_to_string(dir.join(file)).unwrap_or_default();
+        let guid = zfs.list_filesystems(FsType::Bookmark).unwrap()[0].guid();
+
+        // backup/tank was created for the replica of tank/www, which is based on a bookmark.
+        fs::write(
+            dir.join("target"),
+            format!(
+                "backup\t1\t-\nbackup/tank\t2\t-\nbackup/tank/www\t3\t-\n\
+                 backup/tank/www@zfs-snappers_hourly-2022-07-12-0900\t{guid}\t-\n"
+            ),
+        )
+        .unwrap();
+        assert!(run(&zfs, &config));
+        assert_eq!(
+            read("received"),
+            "tank@zfs-snappers_hourly-2022-07-12-1100\n\
+             -i tank/www#zfs-snappers_hourly-2022-07-12-0900 \
+             tank/www@zfs-snappers_hourly-2022-07-12-1000\n\
+             -I tank/www@zfs-snappers_hourly-2022-07-12-1000 \
+             tank/www@zfs-snappers_hourly-2022-07-12-1100\n"
+        );
+        assert!(read("calls").contains("receive -s -u -F backup/tank\n"));
+        assert!(!read("calls").contains("create"));
+
+        // A replica unrelated to the source.
+        fs::write(
+            dir.join("target"),
+            "backup/tank/www\t3\t-\nbackup/tank/www@x\t99\t-\n",
+        )
+        .unwrap();
+        let snapshots = zfs.list_filesystems(FsType::Snapshot).unwrap();
+        let www = &zfs.list_filesystems(FsType::Filesystem).unwrap()[1];
+        let error = Replicator::new(&zfs, &config)
+            .replicate(www, &snapshots, &[])
+            .unwrap_err()
+            .to_string();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.contains("no snapshot in common"));
+        assert!(error.ends_with("to start over by a full send"));
+    }
+
+    #[test]
+    fn keep_the_base_if_it_can_not_be_protected() {
+        let dir = std::env::temp_dir().join(format!("zfs-snappers-protect-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let now = fake::now();
+        let base = "tank/www@zfs-snappers_hourly-2022-07-12-1000";
+        let pool = fake::www()
+            .snapped("tank/www", "hourly", 3, 0)
+            .snapshot(base, now - Duration::hours(2), 0)
+            .snapped("tank/www", "hourly", 1, 0)
+            .deny(base);
+        let zfs = Zfs::new(false, "zfs-snappers", vec!["hourly".into()], now).with_backend(pool);
+        let config = ReplicationConfig {
+            target: String::from("backup"),
+            command: Some(receiver(&dir)),
+        };
+        let snapshots = zfs.list_filesystems(FsType::Snapshot).unwrap();
+        let www = &zfs.list_filesystems(FsType::Filesystem).unwrap()[1];
+        fs::write(
+            dir.join("target"),
+            format!("backup/tank/www@x\t{}\t-\n", snapshots[1].guid()),
+        )
+        .unwrap();
+        let names = |snaps: &[&FS]| snaps.iter().map(|sn| sn.name.clone()).collect::<Vec<_>>();
+
+        // Bookmarking the base is denied.
+        let mut expendable: Vec<&FS> = snapshots.iter().take(2).collect();
+        let replicator = Replicator::new(&zfs, &config);
+        assert!(replicator
+            .protect_base(www, &snapshots, &[], &mut expendable)
+            .is_err());
+        assert_eq!(
+            names(&expendable),
+            ["tank/www@zfs-snappers_hourly-2022-07-12-0900"]
+        );
+
+        // The target is unreachable, every expendable snapshot is bookmarked instead.
+        fs::write(dir.join("down"), "").unwrap();
+        let mut expendable: Vec<&FS> = snapshots.iter().take(2).collect();
+        assert!(Replicator::new(&zfs, &config)
+            .protect_base(www, &snapshots, &[], &mut expendable)
+            .is_err());
+        assert_eq!(
+            names(&expendable),
+            ["tank/www@zfs-snappers_hourly-2022-07-12-0900"]
+        );
+
+        // A bookmark keeps the base, the target is not contacted.
+        let bookmarks = zfs.list_filesystems(FsType::Bookmark).unwrap();
+        let mut expendable: Vec<&FS> = snapshots.iter().take(2).collect();
+        assert!(Replicator::new(&zfs, &config)
+            .protect_base(www, &snapshots, &bookmarks, &mut expendable)
+            .is_ok());
+        assert_eq!(expendable.len(), 2);
+        let calls = fs::read_to_string(dir.join("calls")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(calls.lines().filter(|c| c.starts_with("list")).count(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! report
+//! Records what a run did to every dataset, printed by `--output json` for monitoring.
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+/// Reason of the snapshots skipped because another instance holds the lock of their pool.
+pub const LOCKED: &str = "pool is locked by another instance";
+
+/// Outcome of the snapshot of a label.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Created,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct SnapshotReport {
+    pub label: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Why the snapshot was skipped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A failed operation, like 'snapshot', 'destroy' or 'pre_snapshot'.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct OperationError {
+    pub operation: String,
+    pub message: String,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct DatasetReport {
+    pub snapshots: Vec<SnapshotReport>,
+    pub destroyed: Vec<String>,
+    /// Expendable snapshots kept by the pre-destroy hook.
+    pub vetoed: Vec<String>,
+    pub errors: Vec<OperationError>,
+    /// Seconds spent per operation.
+    pub seconds: BTreeMap<&'static str, f64>,
+}
+
+/// Everything a run evaluated.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    /// Start of the run, RFC 3339.
+    pub started: String,
+    pub dry_run: bool,
+    pub success: bool,
+    pub seconds: f64,
+    /// Errors not belonging to a single dataset, like listing or locking.
+    pub errors: Vec<OperationError>,
+    pub datasets: BTreeMap<String, DatasetReport>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl Report {
+    pub fn new(started: String, dry_run: bool) -> Self {
+        Self {
+            started,
+            dry_run,
+            success: true,
+            seconds: 0.0,
+            errors: Vec::new(),
+            datasets: BTreeMap::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Whether nothing failed.
+    pub fn success(&self) -> bool {
+        self.success
+    }
+
+    /// Whether `label` was handled: nothing failed besides the datasets, and no snapshot of
+    /// it failed or was skipped for a locked pool.
+    pub fn done(&self, label: &str) -> bool {
+        self.errors.is_empty()
+            && self
+                .datasets
+                .values()
+                .flat_map(|ds| &ds.snapshots)
+                .filter(|sn| sn.label == label)
+                .all(|sn| match sn.status {
+                    Status::Created => true,
+                    Status::Skipped => sn.reason.as_deref() != Some(LOCKED),
+                    Status::Failed => false,
+                })
+    }
+
+    fn dataset(&mut self, dataset: &str) -> &mut DatasetReport {
+        self.datasets.entry(dataset.to_owned()).or_default()
+    }
+
+    /// Logs the failed `operation` and records it for every one of `datasets`, or for the
+    /// whole run if none.
+    pub fn error<M: fmt::Display>(&mut self, datasets: &[&str], operation: &str, message: M) {
+        error!("{}", message);
+        self.success = false;
+        let error = || OperationError {
+            operation: operation.to_owned(),
+            message: message.to_string(),
+        };
+        match datasets {
+            [] => self.errors.push(error()),
+            _ => {
+                for dataset in datasets {
+                    self.dataset(dataset).errors.push(error());
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of the snapshot `name` of `label`.
+    pub fn snapshot(&mut self, dataset: &str, label: &str, status: Status, name: Option<String>) {
+        self.dataset(dataset).snapshots.push(SnapshotReport {
+            label: label.to_owned(),
+            status,
+            name,
+            reason: None,
+        });
+    }
+
+    /// Records why no snapshot of `label` was created.
+    pub fn skipped(&mut self, dataset: &str, label: &str, reason: String) {
+        self.dataset(dataset).snapshots.push(SnapshotReport {
+            label: label.to_owned(),
+            status: Status::Skipped,
+            name: None,
+            reason: Some(reason),
+        });
+    }
+
+    pub fn destroyed(&mut self, dataset: &str, snapshot: &str) {
+        self.dataset(dataset).destroyed.push(snapshot.to_owned());
+    }
+
+    pub fn vetoed(&mut self, dataset: &str, snapshot: &str) {
+        self.dataset(dataset).vetoed.push(snapshot.to_owned());
+    }
+
+    /// Adds the time since `since` to `operation` of every one of `datasets`.
+    pub fn time(&mut self, datasets: &[&str], operation: &'static str, since: Instant) {
+        let seconds = since.elapsed().as_secs_f64();
+        for dataset in datasets {
+            *self.dataset(dataset).seconds.entry(operation).or_default() += seconds;
+        }
+    }
+
+    /// Stops the clock of the run.
+    pub fn finish(mut self) -> Self {
+        self.seconds = self.start.elapsed().as_secs_f64();
+        self
+    }
+}
@@ -0,0 +1,355 @@
+//! retention
+//! Decides which snapshots of a dataset are kept and which are expendable.
+use crate::zfs::FS;
+use crate::zone::Zone;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A duration given as number and unit, like '30m', '48h', '90d', '2w' or '1y'.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Age(pub Duration);
+
+impl FromStr for Age {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: i64 = num
+            .parse()
+            .map_err(|_| format!("invalid age '{s}', expected e.g. '48h' or '90d'"))?;
+        let duration = match unit.trim() {
+            "s" => Duration::seconds(num),
+            "m" | "min" => Duration::minutes(num),
+            "h" => Duration::hours(num),
+            "d" => Duration::days(num),
+            "w" => Duration::weeks(num),
+            "y" => Duration::days(num * 365),
+            _ => return Err(format!("invalid unit in age '{s}', use s, m, h, d, w or y")),
+        };
+        Ok(Age(duration))
+    }
+}
+
+impl TryFrom<String> for Age {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Age {
+    /// The largest whole unit, like '3h' or '12d'.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Age(d) = self;
+        match (d.num_days(), d.num_hours(), d.num_minutes()) {
+            (days, _, _) if days >= 14 => write!(f, "{}w", d.num_weeks()),
+            (days, _, _) if days >= 2 => write!(f, "{days}d"),
+            (_, hours, _) if hours >= 2 => write!(f, "{hours}h"),
+            (_, _, minutes) if minutes >= 2 => write!(f, "{minutes}m"),
+            _ => write!(f, "{}s", d.num_seconds()),
+        }
+    }
+}
+
+/// An amount of bytes given as number and unit, like '512K', '10M' or '1G'. A bare number
+/// counts Kibibytes.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "SizeValue")]
+pub struct Size(pub u64);
+
+/// A size in the configuration-file, either a number of Kibibytes or a string with unit.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SizeValue {
+    Kib(u64),
+    Text(String),
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        let num: u64 = num
+            .parse()
+            .map_err(|_| format!("invalid size '{s}', expected e.g. '512K' or '10M'"))?;
+        let exp = match unit.trim().to_ascii_uppercase().as_str() {
+            "B" => 0,
+            "" | "K" | "KB" | "KIB" => 1,
+            "M" | "MB" | "MIB" => 2,
+            "G" | "GB" | "GIB" => 3,
+            "T" | "TB" | "TIB" => 4,
+            _ => return Err(format!("invalid unit in size '{s}', use B, K, M, G or T")),
+        };
+        num.checked_mul(1024u64.pow(exp))
+            .map(Size)
+            .ok_or_else(|| format!("size '{s}' is too large"))
+    }
+}
+
+impl TryFrom<SizeValue> for Size {
+    type Error = String;
+
+    fn try_from(value: SizeValue) -> Result<Self, Self::Error> {
+        match value {
+            SizeValue::Kib(kib) => kib.to_string().parse(),
+            SizeValue::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut size = self.0 as f64;
+        for unit in ["B", "KiB", "MiB", "GiB"] {
+            if size < 1024.0 {
+                return write!(f, "{size:.1} {unit}");
+            }
+            size /= 1024.0;
+        }
+        write!(f, "{size:.1} TiB")
+    }
+}
+
+/// Grandfather-father-son buckets: the number of recent hours, days, weeks, months and
+/// years of which the newest snapshot is kept.
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Gfs {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+    pub yearly: usize,
+}
+
+impl Gfs {
+    /// Marks the snapshots representing a bucket of the calendar in `zone`.
+    fn kept(&self, snaps: &[&FS], zone: Zone) -> Vec<bool> {
+        let mut kept = vec![false; snaps.len()];
+        for (count, bucket) in [
+            (self.hourly, "%Y-%m-%d %H"),
+            (self.daily, "%Y-%m-%d"),
+            (self.weekly, "%G-%V"),
+            (self.monthly, "%Y-%m"),
+            (self.yearly, "%Y"),
+        ] {
+            let mut last: Option<String> = None;
+            let mut found = 0;
+            for (i, sn) in snaps.iter().enumerate().rev() {
+                if found >= count {
+                    break;
+                }
+                let key = zone.at(&sn.date()).format(bucket).to_string();
+                if last.as_ref() != Some(&key) {
+                    kept[i] = true;
+                    found += 1;
+                    last = Some(key);
+                }
+            }
+        }
+        kept
+    }
+}
+
+/// The settings which apply to one dataset and label.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// Number of recent snapshots to keep.
+    pub keep: usize,
+    /// Bytes written since the last snapshot of the label, below no snapshot is created.
+    pub min_size: Size,
+    /// Keeps all snapshots younger than this, in addition to the `keep` recent ones.
+    pub keep_within: Option<Age>,
+    /// Destroys all snapshots older than this, regardless of `keep` and `keep_within`.
+    pub max_age: Option<Age>,
+    /// Thins out the snapshots of all labels of a dataset instead of counting per label.
+    pub gfs: Option<Gfs>,
+    /// Time zone of the days, weeks, months and years of the `gfs` buckets.
+    pub zone: Zone,
+}
+
+impl Policy {
+    /// Returns the snapshots to destroy of `snaps`, sorted by date.
+    pub fn expendable<'a>(&self, snaps: &[&'a FS], now: DateTime<Utc>) -> Vec<&'a FS> {
+        let recent = snaps.len().saturating_sub(self.keep);
+        let gfs = self
+            .gfs
+            .map(|gfs| gfs.kept(snaps, self.zone))
+            .unwrap_or_default();
+        snaps
+            .iter()
+            .enumerate()
+            .filter(|(i, sn)| {
+                let age = now - sn.date();
+                let too_old = self.max_age.is_some_and(|Age(max)| age > max);
+                let within = self.keep_within.is_some_and(|Age(within)| age <= within);
+                let bucket = gfs.get(*i).copied().unwrap_or(false);
+                too_old || !(*i >= recent || within || bucket)
+            })
+            .map(|(_, &sn)| sn)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use crate::zfs::FsType;
+    use chrono::TimeZone;
+
+    fn snaps_aged(now: DateTime<Utc>, days: &[i64]) -> Vec<FS> {
+        days.iter()
+            .map(|d| {
+                FS::new(
+                    &format!("tank@zfs-snappers_daily-{d}"),
+                    FsType::Snapshot,
+                    now - Duration::days(*d),
+                )
+            })
+            .collect()
+    }
+
+    fn names(snaps: Vec<&FS>) -> Vec<&str> {
+        snaps.iter().map(|sn| sn.name.as_str()).collect()
+    }
+
+    #[test]
+    fn parse_ages() {
+        assert_eq!("48h".parse(), Ok(Age(Duration::hours(48))));
+        assert_eq!("90d".parse(), Ok(Age(Duration::days(90))));
+        assert_eq!("2w".parse(), Ok(Age(Duration::weeks(2))));
+        assert!("90".parse::<Age>().is_err());
+        assert!("d".parse::<Age>().is_err());
+        assert!("3 fortnights".parse::<Age>().is_err());
+        assert_eq!(Age(Duration::minutes(150)).to_string(), "2h");
+        assert_eq!(Age(Duration::days(90)).to_string(), "12w");
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!("1024".parse(), Ok(Size(1024 * 1024)));
+        assert_eq!("512K".parse(), Ok(Size(512 * 1024)));
+        assert_eq!("10M".parse(), Ok(Size(10 * 1024 * 1024)));
+        assert_eq!("1GiB".parse(), Ok(Size(1024 * 1024 * 1024)));
+        assert_eq!("100B".parse(), Ok(Size(100)));
+        assert!("M".parse::<Size>().is_err());
+        assert!("10 parsecs".parse::<Size>().is_err());
+        assert_eq!(Size(10 * 1024 * 1024).to_string(), "10.0 MiB");
+    }
+
+    #[test]
+    fn keep_count_and_within() {
+        let now = Utc.ymd(2022, 7, 12).and_hms(12, 0, 0);
+        let all = snaps_aged(now, &[10, 5, 3, 1, 0]);
+        let snaps: Vec<&FS> = all.iter().collect();
+        let mut policy = Policy {
+            keep: 2,
+            min_size: Size(0),
+            keep_within: None,
+            max_age: None,
+            gfs: None,
+            zone: Zone::Utc,
+        };
+        assert_eq!(
+            names(policy.expendable(&snaps, now)),
+            [
+                "tank@zfs-snappers_daily-10",
+                "tank@zfs-snappers_daily-5",
+                "tank@zfs-snappers_daily-3"
+            ]
+        );
+        policy.keep_within = Some(Age(Duration::days(4)));
+        assert_eq!(
+            names(policy.expendable(&snaps, now)),
+            ["tank@zfs-snappers_daily-10", "tank@zfs-snappers_daily-5"]
+        );
+        policy.keep = 8;
+        policy.max_age = Some(Age(Duration::days(7)));
+        assert_eq!(
+            names(policy.expendable(&snaps, now)),
+            ["tank@zfs-snappers_daily-10"]
+        );
+    }
+
+    #[test]
+    fn thin_out_by_gfs_buckets() {
+        let now = Utc.ymd(2022, 7, 12).and_hms(12, 30, 0);
+        let hours = [24 * 40, 24 * 9, 24 * 8, 50, 49, 26, 25, 2, 1, 0];
+        let all: Vec<FS> = hours
+            .iter()
+            .map(|h| {
+                FS::new(
+                    &format!("tank@zfs-snappers_hourly-{h}"),
+                    FsType::Snapshot,
+                    now - Duration::hours(*h),
+                )
+            })
+            .collect();
+        let snaps: Vec<&FS> = all.iter().collect();
+        let policy = Policy {
+            keep: 0,
+            min_size: Size(0),
+            keep_within: None,
+            max_age: None,
+            gfs: Some(Gfs {
+                hourly: 2,
+                daily: 3,
+                weekly: 2,
+                monthly: 2,
+                ..Gfs::default()
+            }),
+            zone: Zone::Utc,
+        };
+        assert_eq!(
+            names(policy.expendable(&snaps, now)),
+            [
+                "tank@zfs-snappers_hourly-216",
+                "tank@zfs-snappers_hourly-192",
+                "tank@zfs-snappers_hourly-50",
+                "tank@zfs-snappers_hourly-26",
+                "tank@zfs-snappers_hourly-2"
+            ]
+        );
+    }
+
+    #[test]
+    fn bucket_by_calendar_of_zone() {
+        let now = Utc.ymd(2022, 7, 12).and_hms(12, 0, 0);
+        let all = [21, 22].map(|h| {
+            FS::new(
+                &format!("tank@zfs-snappers_hourly-{h}"),
+                FsType::Snapshot,
+                Utc.ymd(2022, 7, 11).and_hms(h, 30, 0),
+            )
+        });
+        let snaps: Vec<&FS> = all.iter().collect();
+        let mut policy = Policy {
+            keep: 0,
+            min_size: Size(0),
+            keep_within: None,
+            max_age: None,
+            gfs: Some(Gfs {
+                daily: 2,
+                ..Gfs::default()
+            }),
+            zone: Zone::Utc,
+        };
+        assert_eq!(
+            names(policy.expendable(&snaps, now)),
+            ["tank@zfs-snappers_hourly-21"]
+        );
+        // 23:30 and 00:30 in Berlin, on two days.
+        policy.zone = "Europe/Berlin".parse().unwrap();
+        assert!(policy.expendable(&snaps, now).is_empty());
+    }
+}
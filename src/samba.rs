@@ -0,0 +1,83 @@
+//! samba
+//! Settings of Samba's vfs_shadow_copy2, offering the snapshots as "Previous Versions".
+use crate::config::Config;
+use crate::zfs::{FsType, Zfs};
+use log::error;
+
+/// Escapes the special characters of a POSIX basic regular expression.
+fn escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '.' | '*' | '[' | ']' | '^' | '$' | '\\' => vec!['\\', c],
+            c => vec![c],
+        })
+        .collect()
+}
+
+/// A share of `dataset` mounted at `path`, with the snapshots named by the shadow_copy2
+/// preset. It is named by the whole dataset, like `tank_srv_www`, so no two shares collide.
+fn share(prefix: &str, dataset: &str, path: &str) -> String {
+    let name = dataset.replace('/', "_");
+    [
+        format!("# {dataset}"),
+        format!("[{name}]"),
+        format!("    path = {path}"),
+        String::from("    vfs objects = shadow_copy2"),
+        String::from("    shadow:snapdir = .zfs/snapshot"),
+        String::from("    shadow:sort = desc"),
+        format!("    shadow:snapprefix = ^{}_.*", escape(prefix)),
+        String::from("    shadow:delimiter = _GMT"),
+        String::from("    shadow:format = _GMT-%Y.%m.%d-%H.%M.%S"),
+        String::from("    shadow:localtime = no"),
+    ]
+    .join("\n")
+}
+
+/// Prints the smb.conf-shares of the mounted datasets with auto-snapshots enabled. The
+/// configuration-file has to name the snapshots by `template = "shadow_copy2"`.
+pub fn run(zfs: &Zfs, config: &Config) -> bool {
+    if !config.template.is_shadow_copy2() {
+        error!(
+            "template '{}' is not parseable by shadow_copy2, configure template = \"shadow_copy2\"",
+            config.template
+        );
+        return false;
+    }
+    let listed = zfs
+        .list_filesystems(FsType::Filesystem)
+        .and_then(|filesystems| Ok((filesystems, zfs.mountpoints()?)));
+    let (filesystems, mountpoints) = match listed {
+        Ok(listed) => listed,
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    };
+    let shares: Vec<String> = filesystems
+        .iter()
+        .filter(|fs| fs.managed())
+        .map(|fs| match mountpoints.get(&fs.name).map(String::as_str) {
+            Some(path) if path.starts_with('/') => share(&config.prefix, &fs.name, path),
+            _ => format!("# {}: not mounted by zfs", fs.name),
+        })
+        .collect();
+    // shadow_copy2 parses no suffix like '.2'.
+    println!("# A snapshot taken twice within a second is named a second later.\n");
+    println!("{}", shares.join("\n\n"));
+    true
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+
+    #[test]
+    fn configure_shadow_copy2() {
+        let share = share("zfs.snappers", "tank/www", "/srv/www");
+        assert!(share.starts_with("# tank/www\n[tank_www]\n    path = /srv/www\n"));
+        assert!(share.contains("    shadow:snapprefix = ^zfs\\.snappers_.*\n"));
+        assert!(share.ends_with("    shadow:localtime = no"));
+        let nested = super::share("zfs-snappers", "tank/srv/www", "/srv/www");
+        assert!(nested.contains("\n[tank_srv_www]\n"));
+    }
+}
@@ -0,0 +1,125 @@
+//! schedule
+//! Decides when the snapshots of a label are due.
+use crate::zone::Zone;
+use chrono::{DateTime, Local, Utc};
+use std::str::FromStr;
+
+/// Cron-expression for the well known labels.
+fn keyword(name: &str) -> Option<&'static str> {
+    match name {
+        "frequent" => Some("0 */15 * * * *"),
+        "hourly" => Some("0 0 * * * *"),
+        "daily" => Some("0 0 0 * * *"),
+        "weekly" => Some("0 0 0 * * Mon"),
+        "monthly" => Some("0 0 0 1 * *"),
+        "yearly" => Some("0 0 0 1 1 *"),
+        _ => None,
+    }
+}
+
+/// A schedule given as keyword ('hourly', 'daily', ...) or cron-expression. Cron-expressions
+/// with five fields are extended by a leading seconds-field. They are evaluated in the time
+/// zone of the schedule, UTC by default.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    cron: cron::Schedule,
+    zone: Zone,
+}
+
+impl Schedule {
+    /// Default schedule of a label, if its name is one of the keywords.
+    pub fn for_label(label: &str) -> Option<Self> {
+        keyword(label).and_then(|expr| expr.parse().ok())
+    }
+
+    /// This schedule evaluated in `zone`, 'daily' runs at local midnight.
+    pub fn in_zone(self, zone: Zone) -> Self {
+        Self { zone, ..self }
+    }
+
+    /// The first scheduled point in time after `time`.
+    fn after(&self, time: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self.zone {
+            Zone::Utc => self.cron.after(time).next(),
+            Zone::Local => self
+                .cron
+                .after(&time.with_timezone(&Local))
+                .next()
+                .map(|t| t.with_timezone(&Utc)),
+            Zone::Named(tz) => self
+                .cron
+                .after(&time.with_timezone(&tz))
+                .next()
+                .map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    /// Whether a scheduled point in time has passed since `last_run`. A label which never
+    /// ran is due immediately, a label whose runs were missed is due once.
+    pub fn is_due(&self, last_run: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match last_run {
+            None => true,
+            Some(last) => self.after(&last).is_some_and(|next| next <= now),
+        }
+    }
+
+    /// The next scheduled point in time after `now`.
+    pub fn next(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.after(&now)
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match keyword(s) {
+            Some(expr) => expr.to_owned(),
+            None if s.split_whitespace().count() == 5 => format!("0 {s}"),
+            None => s.to_owned(),
+        };
+        cron::Schedule::from_str(&expr)
+            .map(|cron| Schedule {
+                cron,
+                zone: Zone::Utc,
+            })
+            .map_err(|e| format!("invalid schedule '{s}': {e}"))
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn parse_keywords_and_expressions() {
+        assert!(Schedule::for_label("hourly").is_some());
+        assert!(Schedule::for_label("backup").is_none());
+        assert!("*/5 * * * *".parse::<Schedule>().is_ok());
+        assert!("0 30 2 * * Sun".parse::<Schedule>().is_ok());
+        assert!("every now and then".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn catch_up_missed_runs_once() {
+        let daily: Schedule = "daily".parse().unwrap();
+        let now = Utc.ymd(2022, 7, 12).and_hms(10, 0, 0);
+        assert!(daily.is_due(None, now));
+        assert!(!daily.is_due(Some(Utc.ymd(2022, 7, 12).and_hms(0, 0, 0)), now));
+        assert!(daily.is_due(Some(Utc.ymd(2022, 7, 9).and_hms(0, 0, 0)), now));
+        assert_eq!(daily.next(now), Some(Utc.ymd(2022, 7, 13).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn run_at_midnight_of_zone() {
+        let daily = Schedule::for_label("daily")
+            .unwrap()
+            .in_zone("Europe/Berlin".parse().unwrap());
+        let now = Utc.ymd(2022, 7, 12).and_hms(10, 0, 0);
+        assert_eq!(
+            daily.next(now),
+            Some(Utc.ymd(2022, 7, 12).and_hms(22, 0, 0))
+        );
+    }
+}
@@ -1,11 +1,22 @@
-///! zfs
-///! Helper-objects for dealing with zfs.  
+//! zfs
+//! Helper-objects for dealing with zfs.
+use crate::backend::{Cli, ZfsBackend};
+use crate::config::Config;
+use crate::name::{self, Parsed, Pattern, Scheme, Template};
+use crate::retention::{Policy, Size};
+use crate::zone::Zone;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::ffi::OsStr;
 use std::fmt;
 use std::process;
+use std::rc::Rc;
+
+/// Longest argument of a `zfs destroy fs@a,b,c`, half of the `MAX_ARG_STRLEN` of Linux.
+const MAX_DESTROY_LEN: usize = 64 * 1024;
 
 #[derive(Eq, PartialEq, Debug)]
 pub struct FS {
@@ -13,27 +24,105 @@ pub struct FS {
     date: DateTime<Utc>,
     fs_type: FsType,
     pub snap: bool,
-    written: usize,
+    labels: Vec<String>,
+    written: u64,
+    used: u64,
+    guid: u64,
     fs: String,
 }
 
+impl FS {
+    #[cfg(test)]
+    pub fn new(name: &str, fs_type: FsType, date: DateTime<Utc>) -> Self {
+        FS {
+            name: name.to_owned(),
+            date,
+            fs_type,
+            snap: false,
+            labels: Vec::new(),
+            written: 0,
+            used: 0,
+            guid: 0,
+            fs: name.split(['@', '#']).next().unwrap().to_owned(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_guid(self, guid: u64) -> Self {
+        FS { guid, ..self }
+    }
+
+    /// Name of the filesystem, also of a snapshot or bookmark.
+    pub fn dataset(&self) -> &str {
+        &self.fs
+    }
+
+    /// Name of a snapshot or bookmark without the filesystem.
+    pub fn short_name(&self) -> &str {
+        self.name
+            .split_once(['@', '#'])
+            .map_or(self.name.as_str(), |(_, name)| name)
+    }
+
+    /// Creation-time.
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    /// Filesystem, snapshot or bookmark.
+    pub fn fs_type(&self) -> FsType {
+        self.fs_type
+    }
+
+    /// Bytes only referenced by this snapshot, freed by destroying it.
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Unique identifier, identical for a snapshot and its received copies.
+    pub fn guid(&self) -> u64 {
+        self.guid
+    }
+
+    /// Whether auto-snapshots are enabled for any label.
+    pub fn managed(&self) -> bool {
+        self.snap || !self.labels.is_empty()
+    }
+
+    /// Whether auto-snapshots are enabled for `label`, either in general or by the
+    /// label-specific option.
+    pub fn snap_for(&self, label: &str) -> bool {
+        self.snap || self.labels.iter().any(|l| l == label)
+    }
+}
+
 impl fmt::Display for FS {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "DS {} = {} ({} KB)", self.name, self.snap, self.written)
+        write!(
+            f,
+            "DS {} = {} ({})",
+            self.name,
+            self.snap,
+            Size(self.written)
+        )
     }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
 pub enum FsType {
     Filesystem,
+    Volume,
     Snapshot,
+    Bookmark,
 }
 
 impl FsType {
-    fn as_str(&self) -> &'static str {
+    pub fn as_str(&self) -> &'static str {
         match self {
             FsType::Snapshot => "snapshot",
             FsType::Filesystem => "filesystem",
+            FsType::Volume => "volume",
+            FsType::Bookmark => "bookmark",
         }
     }
 }
@@ -48,157 +137,518 @@ impl fmt::Display for FsType {
 ///
 ///
 pub struct Zfs {
-    executable: String,
+    backend: Box<dyn ZfsBackend>,
     pretend: bool,
     prefix: String,
     option_name: String,
-    label: String,
-    history: usize,
-    timestamp: String,
+    labels: Vec<String>,
+    now: DateTime<Utc>,
+    template: Template,
+    /// Time zone of the timestamps in the names.
+    zone: Zone,
+    hostname: String,
+    /// Templates of other tools, whose snapshots are managed as well.
+    adopted: Vec<Template>,
+    /// Compiled patterns of the template and the adopted ones, by label.
+    patterns: RefCell<HashMap<Option<String>, Rc<Vec<Pattern>>>>,
 }
 
 impl Zfs {
-    pub fn new<P, L, D>(pretend: bool, prefix: P, label: L, history: usize, date: D) -> Self
+    pub fn new<P>(pretend: bool, prefix: P, labels: Vec<String>, now: DateTime<Utc>) -> Self
     where
         P: Into<String>,
-        L: Into<String>,
-        D: Into<String>,
     {
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
         Self {
-            executable: default_exec(),
+            backend: Box::new(Cli::new(default_exec())),
             pretend,
             prefix: prefix.into(),
             option_name: String::from("com.sun:auto-snapshot"),
-            label: label.into(),
-            history,
-            timestamp: date.into(),
+            labels,
+            now,
+            template: Template::default(),
+            zone: Zone::Utc,
+            hostname,
+            adopted: Vec::new(),
+            patterns: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Handles the snapshots of `labels` named and adopted as configured by `config`.
+    pub fn from_config(
+        config: &Config,
+        pretend: bool,
+        labels: Vec<String>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self::new(pretend, &config.prefix, labels, now)
+            .with_naming(config.template.clone(), config.timezone)
+            .with_adopted(&config.adopt)
+    }
+
+    /// Names the snapshots by `template`, with the timestamps in `zone`.
+    fn with_naming(self, template: Template, zone: Zone) -> Self {
+        Self {
+            template,
+            zone,
+            patterns: RefCell::new(HashMap::new()),
+            ..self
+        }
+    }
+
+    /// Adopts the snapshots named by the `schemes` of other tools: they count towards the
+    /// retention of their label and are pruned like the own ones.
+    fn with_adopted(self, schemes: &[Scheme]) -> Self {
+        Self {
+            adopted: schemes.iter().map(Scheme::template).collect(),
+            patterns: RefCell::new(HashMap::new()),
+            ..self
+        }
+    }
+
+    /// Replaces the zfs-binary by another backend, e.g. a simulated pool.
+    #[cfg(test)]
+    pub fn with_backend<B: ZfsBackend + 'static>(self, backend: B) -> Self {
+        Self {
+            backend: Box::new(backend),
+            ..self
         }
     }
 
-    fn cmd(&self) -> process::Command {
-        process::Command::new(&self.executable)
+    /// Pretending, not really changing anything.
+    pub fn pretend(&self) -> bool {
+        self.pretend
     }
 
-    fn sname(&self, name: &str, timestamp: Option<&str>) -> String {
-        let Self { prefix, label, .. } = self;
-        let ts = match timestamp {
-            None => self.timestamp.clone(),
-            Some(s) => s.into(),
+    /// Time of this run.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+
+    /// Labels handled by this instance.
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Patterns of the names of `label`, or of any label if `None`: the one of the template
+    /// followed by the adopted ones.
+    fn patterns(&self, label: Option<&str>) -> Rc<Vec<Pattern>> {
+        let key = label.map(String::from);
+        let mut patterns = self.patterns.borrow_mut();
+        let patterns = patterns.entry(key).or_insert_with(|| {
+            let templates = std::iter::once(&self.template).chain(&self.adopted);
+            let mut patterns: Vec<Pattern> = templates
+                .map(|t| t.pattern(&self.prefix, &self.hostname, label))
+                .collect();
+            // The default keeps managing the snapshots of the previous releases.
+            if self.template.is_default() {
+                patterns.push(name::legacy_pattern(&self.prefix, label));
+            }
+            Rc::new(patterns)
+        });
+        Rc::clone(patterns)
+    }
+
+    /// Parses the name of a snapshot of `label`, or of any label if `None`, the part after
+    /// the '@'. Returns `None` if it is neither named by the template nor adopted.
+    fn parse(&self, label: Option<&str>, name: &str) -> Option<Parsed> {
+        self.patterns(label).iter().find_map(|p| p.parse(name))
+    }
+
+    /// Whether `name` is of a snapshot of `label`, or of any label if `None`.
+    fn recognises(&self, label: Option<&str>, name: &str) -> bool {
+        self.patterns(label).iter().any(|p| p.is_match(name))
+    }
+
+    /// Name of the new snapshots of `batch` after the '@', with the first free suffix `.2`,
+    /// `.3`, ... if it is taken. Names of shadow_copy2 take the first free later second.
+    pub fn snapshot_name(&self, label: &str, batch: &[&FS], snaps: &[FS]) -> String {
+        let seq = match self.template.has_seq() {
+            true => {
+                batch
+                    .iter()
+                    .flat_map(|fs| self.filter_snaps(Some(label), fs, snaps))
+                    .filter_map(|sn| self.parse(Some(label), sn.short_name())?.seq)
+                    .max()
+                    .unwrap_or(0)
+                    + 1
+            }
+            false => 1,
         };
-        format!("{name}@{prefix}_{label}-{ts}")
+        let render = |time: &DateTime<Utc>| {
+            let (prefix, hostname) = (&self.prefix, &self.hostname);
+            self.template
+                .render(prefix, label, hostname, time, self.zone, seq)
+        };
+        let name = render(&self.now);
+        let taken = |name: &str| {
+            snaps.iter().any(|sn| {
+                sn.fs_type == FsType::Snapshot
+                    && sn.short_name() == name
+                    && batch.iter().any(|fs| fs.name == sn.fs)
+            })
+        };
+        if !taken(&name) {
+            return name;
+        }
+        let unique = match self.template.is_shadow_copy2() {
+            true => (1..)
+                .map(|s| render(&(self.now + chrono::Duration::seconds(s))))
+                .find(|name| !taken(name))
+                .unwrap(),
+            false => (2..)
+                .map(|n| Template::disambiguate(&name, n))
+                .find(|name| !taken(name))
+                .unwrap(),
+        };
+        info!("snapshot {} exists, naming the new one {}", name, unique);
+        unique
     }
 
-    fn filter_snaps<'a>(&self, fs: &FS, snaps: &'a [FS]) -> Vec<&'a FS> {
-        let name = self.sname(&fs.name, Some(""));
+    /// Returns the snapshots (or bookmarks) of `fs` with the given label, or with any label
+    /// if `None`, recognised by the pattern of the template or an adopted one.
+    pub fn filter_snaps<'a>(&self, label: Option<&str>, fs: &FS, snaps: &'a [FS]) -> Vec<&'a FS> {
         // filter snaps-list fitting to fs.
         let mut snaps: Vec<&FS> = snaps
             .iter()
-            .filter(|&sn| sn.name.strip_prefix(name.as_str()).is_some())
+            .filter(|&sn| sn.fs_type != FsType::Filesystem && sn.fs == fs.name)
+            .filter(|&sn| self.recognises(label, sn.short_name()))
             .collect();
         // Sort descending by FS.date
-        debug!("filter snapshots for '{}', found {}", name, snaps.len());
-        snaps.sort_unstable_by(|&a, &b| a.date.cmp(&b.date));
+        debug!(
+            "filter snapshots of '{}' for {:?}, found {}",
+            fs.name,
+            label,
+            snaps.len()
+        );
+        // Reruns within the same second are ordered by their suffix.
+        snaps.sort_by_cached_key(|&sn| {
+            let dup = self.parse(label, sn.short_name()).and_then(|p| p.dup);
+            (sn.date, dup.unwrap_or(1))
+        });
         snaps
     }
 
-    /// Returns a list of snapshots to destroy.
+    /// Returns the oldest creation-time of the newest snapshots of `label` on the filesystems
+    /// with it enabled, `None` if one of them has none.
+    pub fn last_snapshot(
+        &self,
+        label: &str,
+        filesystems: &[FS],
+        snaps: &[FS],
+    ) -> Option<DateTime<Utc>> {
+        filesystems
+            .iter()
+            .filter(|fs| fs.snap_for(label))
+            .map(|fs| Some(self.filter_snaps(Some(label), fs, snaps).last()?.date))
+            .min()
+            .flatten()
+    }
+
+    /// Returns the snapshots (or bookmarks) of all labels of `fs`, sorted ascending by date.
+    pub fn managed_snapshots<'a>(&self, fs: &FS, snaps: &'a [FS]) -> Vec<&'a FS> {
+        self.filter_snaps(None, fs, snaps)
+    }
+
+    /// The labels in the names of `snaps` named by the template or adopted.
+    pub fn labels_of<'a>(&self, snaps: impl IntoIterator<Item = &'a FS>) -> BTreeSet<String> {
+        snaps
+            .into_iter()
+            .filter_map(|sn| Some(self.parse(None, sn.short_name())?.label))
+            .collect()
+    }
+
+    /// Returns a list of snapshots to destroy. With grandfather-father-son buckets in the
+    /// policy the snapshots of all labels are thinned out together: a snapshot is expendable
+    /// only if the policy of every label with snapshots on `fs` gives it up, no matter which
+    /// label is being handled.
     ///
     /// # Arguments
     ///
+    /// * label - label of the snapshots
+    /// * policy - the policy of a label, decides by count and age which snapshots to keep
     /// * fs - filesystem to snap over
     /// * snaps - List of snapshots, to filter and return.
     ///
-    pub fn find_expendable_snapshots<'a>(&'a self, fs: &FS, snaps: &'a [FS]) -> Vec<&'a FS> {
-        let snaps = self.filter_snaps(fs, snaps);
-        if snaps.len() > self.history {
-            snaps
-                .iter()
-                .take(snaps.len() - self.history)
-                .copied()
-                .collect()
-        } else {
-            Vec::new()
+    pub fn find_expendable_snapshots<'a, P>(
+        &'a self,
+        label: &str,
+        policy: P,
+        fs: &FS,
+        snaps: &'a [FS],
+    ) -> Vec<&'a FS>
+    where
+        P: Fn(&str) -> Policy,
+    {
+        let own = policy(label);
+        if own.gfs.is_none() {
+            return own.expendable(&self.filter_snaps(Some(label), fs, snaps), self.now);
         }
+        let snaps = self.filter_snaps(None, fs, snaps);
+        self.labels_of(snaps.iter().copied())
+            .iter()
+            .map(|label| policy(label).expendable(&snaps, self.now))
+            .reduce(|all, expendable| {
+                all.into_iter()
+                    .filter(|sn| expendable.contains(sn))
+                    .collect()
+            })
+            .unwrap_or_default()
     }
 
-    pub fn next_snapshot_needed<'a>(&'a self, min_size: usize, fs: &FS, snaps: &'a [FS]) -> bool {
-        let snaps = self.filter_snaps(fs, snaps);
-        match snaps.last() {
-            Some(&fs) => fs.written >= min_size,
-            None => true,
+    /// Bytes written to `fs` since the newest snapshot of each of its labels, `None` for a
+    /// label without one. The `written` property of `fs` counts since its newest snapshot of
+    /// any label or tool, the older ones are asked for by a single `zfs get written@...`.
+    pub fn written_since(
+        &self,
+        fs: &FS,
+        snaps: &[FS],
+    ) -> Result<BTreeMap<String, Option<u64>>, ZfsError> {
+        let is_snapshot = |sn: &&FS| sn.fs_type == FsType::Snapshot && sn.fs == fs.name;
+        let newest = snaps.iter().filter(is_snapshot).map(|sn| sn.date).max();
+        let mut written = BTreeMap::new();
+        let mut asked = Vec::new();
+        for label in self.labels.iter().filter(|label| fs.snap_for(label)) {
+            let last = self
+                .filter_snaps(Some(label), fs, snaps)
+                .into_iter()
+                .rfind(is_snapshot);
+            match last {
+                Some(last) if newest > Some(last.date) => {
+                    asked.push((label, format!("written@{}", last.short_name())))
+                }
+                Some(_) => {
+                    written.insert(label.clone(), Some(fs.written));
+                }
+                None => {
+                    written.insert(label.clone(), None);
+                }
+            }
+        }
+        if asked.is_empty() {
+            return Ok(written);
         }
+        let properties: Vec<&str> = asked.iter().map(|(_, p)| p.as_str()).collect();
+        let values = self.backend.get(&properties, &fs.name)?;
+        for ((label, property), value) in asked.iter().zip(values) {
+            let bytes = value.parse().map_err(|_| {
+                ZfsError::InternalError(format!("{}: unexpected {} '{}'", fs.name, property, value))
+            })?;
+            written.insert((*label).clone(), Some(bytes));
+        }
+        Ok(written)
+    }
+
+    /// Whether at least `min_size` bytes were written since the newest snapshot of `label`,
+    /// with these bytes from `written_since`. Without such a snapshot it is needed.
+    pub fn next_snapshot_needed(
+        &self,
+        label: &str,
+        min_size: Size,
+        written: &BTreeMap<String, Option<u64>>,
+    ) -> (bool, Option<u64>) {
+        let written = written.get(label).copied().flatten();
+        (written.is_none_or(|w| Size(w) >= min_size), written)
+    }
+
+    /// Returns the mountpoints of the filesystems, 'none' or 'legacy' if not mounted by zfs.
+    pub fn mountpoints(&self) -> Result<BTreeMap<String, String>, ZfsError> {
+        let stdout = self.backend.list("name,mountpoint", FsType::Filesystem)?;
+        Ok(stdout
+            .lines()
+            .filter_map(|l| l.split_once('\t'))
+            .map(|(name, mountpoint)| (name.to_owned(), mountpoint.to_owned()))
+            .collect())
     }
 
-    /// Returns a list of the filesystems provided by the local zfs.
+    /// Returns a list of the filesystems provided by the local zfs. The label-specific
+    /// options of all labels and the guid are read with the same call.
     ///
     /// # Arguments
     ///
     /// * fst - filesystem-type
     ///
-    pub fn list_filesystems(&self, fst: FsType) -> Vec<FS> {
-        let args = [
-            "list",
-            "-Hp",
-            "-o",
-            &format!(
-                "name,used,{op},{op}:{suf},creation",
-                op = &self.option_name,
-                suf = &self.label
-            ),
-            "-t",
-            fst.as_str(),
-        ];
-        debug!("zfs {}", args.join(" "));
-        let ret = self
-            .cmd()
-            .args(&args)
-            .output()
-            .expect("failed to execute process");
-        let stdout = String::from_utf8(ret.stdout).unwrap();
+    pub fn list_filesystems(&self, fst: FsType) -> Result<Vec<FS>, ZfsError> {
+        let mut columns = vec![String::from("name"), String::from("written")];
+        columns.push(self.option_name.clone());
+        for label in &self.labels {
+            columns.push(format!("{}:{}", self.option_name, label));
+        }
+        columns.push(String::from("creation"));
+        columns.push(String::from("guid"));
+        columns.push(String::from("used"));
+        let stdout = self.backend.list(&columns.join(","), fst)?;
         let lines: Vec<FS> = stdout
             .split('\n')
             .filter(|l| !l.is_empty())
-            .map(|t| str2fs(t, fst))
+            .map(|t| str2fs(t, fst, &self.labels))
             .collect();
-        lines
+        Ok(lines)
     }
 
-    /// Creates a snapshots.
+    /// Creates the snapshots of several filesystems atomically, by a single `zfs snapshot`.
+    /// If every given filesystem comes with all its descendants, they are snapped by `-r`.
+    /// A volume below is never part of `filesystems`, so `-r` is not used over volumes.
     ///
     /// # Arguments
     ///
-    /// * fs - filesystem to snap over
+    /// * snapshot - name of the snapshots, the part after the '@'
+    /// * group - filesystems to snap over
+    /// * filesystems - list of all filesystems
     ///
-    pub fn create_snapshot(&self, fs: &FS) -> Result<(), std::io::Error> {
-        let name = self.sname(&fs.name, None);
-        let args = &["snapshot", name.as_str()];
-        info!("zfs {}", args.join(" "));
-        if !self.pretend {
-            let output = self.cmd().args(args).output()?;
-            debug!("{:?}", output);
+    pub fn create_snapshots(
+        &self,
+        snapshot: &str,
+        group: &[&FS],
+        filesystems: &[FS],
+    ) -> Result<(), ZfsError> {
+        let within = |name: &str, top: &str| {
+            name == top || name.strip_prefix(top).is_some_and(|r| r.starts_with('/'))
+        };
+        let tops: Vec<&FS> = group
+            .iter()
+            .filter(|fs| {
+                !group
+                    .iter()
+                    .any(|p| p.name != fs.name && within(&fs.name, &p.name))
+            })
+            .copied()
+            .collect();
+        let subtrees: usize = tops
+            .iter()
+            .map(|top| {
+                filesystems
+                    .iter()
+                    .filter(|f| within(&f.name, &top.name))
+                    .count()
+            })
+            .sum();
+        let recursive = group.len() > tops.len()
+            && subtrees == group.len()
+            && match self.backend.list("name", FsType::Volume) {
+                Ok(volumes) => !volumes
+                    .lines()
+                    .any(|v| tops.iter().any(|top| within(v, &top.name))),
+                Err(e) => {
+                    warn!("{}, snapping without -r", e);
+                    false
+                }
+            };
+        let snapped = if recursive { &tops } else { group };
+        let names: Vec<String> = snapped
+            .iter()
+            .map(|fs| format!("{}@{}", fs.name, snapshot))
+            .collect();
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        info!(
+            "zfs snapshot {}{}",
+            if recursive { "-r " } else { "" },
+            names.join(" ")
+        );
+        match self.pretend {
+            true => Ok(()),
+            false => self.backend.snapshot(&names, recursive),
         }
-        Ok(())
     }
 
-    /// Remove the given filesystem.
+    /// Starts `zfs send <source>`, the stream is readable from its stdout.
+    pub fn send(&self, source: &[&str]) -> Result<process::Child, ZfsError> {
+        info!("zfs send {}", source.join(" "));
+        self.backend.send(source)
+    }
+
+    /// Estimated size of a `zfs send`-stream in bytes, see `send`.
+    pub fn send_size(&self, source: &[&str]) -> Option<u64> {
+        self.backend.send_size(source)
+    }
+
+    /// Creates a bookmark of a snapshot, named like the snapshot.
+    pub fn create_bookmark(&self, snap: &FS) -> Result<(), ZfsError> {
+        let name = snap.name.replacen('@', "#", 1);
+        info!("zfs bookmark {} {}", snap.name, name);
+        match self.pretend {
+            true => Ok(()),
+            false => self.backend.bookmark(&snap.name, &name),
+        }
+    }
+
+    /// Removes snapshots and bookmarks, per dataset by `zfs destroy fs@a,b,c` or one by one
+    /// if that fails. Returns the destroyed snapshots and the errors.
+    pub fn remove_snapshots<'a>(&self, snaps: &[&'a FS]) -> (Vec<&'a FS>, Vec<ZfsError>) {
+        let mut destroyed = Vec::new();
+        let mut one_by_one = Vec::new();
+        let mut batches: Vec<(&str, Vec<&FS>)> = Vec::new();
+        let mut per_dataset: BTreeMap<&str, Vec<&FS>> = BTreeMap::new();
+        for &sn in snaps {
+            match sn.fs_type {
+                FsType::Snapshot => per_dataset.entry(sn.fs.as_str()).or_default().push(sn),
+                _ => one_by_one.push(sn),
+            }
+        }
+        // Splits the comma-lists, which are a single argument of limited length.
+        for (dataset, snaps) in per_dataset {
+            let mut len = 0;
+            for sn in snaps {
+                let short = sn.short_name().len() + 1;
+                match batches.last_mut() {
+                    Some((fs, batch)) if *fs == dataset && len + short <= MAX_DESTROY_LEN => {
+                        batch.push(sn)
+                    }
+                    _ => {
+                        batches.push((dataset, vec![sn]));
+                        len = dataset.len();
+                    }
+                }
+                len += short;
+            }
+        }
+        for (dataset, batch) in batches {
+            if batch.len() == 1 {
+                one_by_one.extend(batch);
+                continue;
+            }
+            let names: Vec<&str> = batch.iter().map(|sn| sn.short_name()).collect();
+            let name = format!("{}@{}", dataset, names.join(","));
+            info!("zfs destroy {}", name);
+            let result = match self.pretend {
+                true => Ok(()),
+                false => self.backend.destroy(&name),
+            };
+            match result {
+                Ok(()) => destroyed.extend(batch),
+                Err(e) => {
+                    debug!("{}, destroying one by one", e);
+                    one_by_one.extend(batch);
+                }
+            }
+        }
+        let mut errors = Vec::new();
+        for sn in one_by_one {
+            match self.remove_snapshot(sn) {
+                Ok(()) => destroyed.push(sn),
+                Err(e) => errors.push(e),
+            }
+        }
+        (destroyed, errors)
+    }
+
+    /// Remove the given snapshot or bookmark.
     ///
     /// # Arguments
     ///
-    /// * fs - filesystem to destroy
+    /// * fs - snapshot or bookmark to destroy
     ///
     pub fn remove_snapshot(&self, fs: &FS) -> Result<(), ZfsError> {
-        if !fs.snap {
+        if matches!(fs.fs_type, FsType::Filesystem | FsType::Volume) {
             return Err(ZfsError::InternalError(
                 "Filesystems can't be removed!".into(),
             ));
         }
-        let args = &["destroy", &fs.name];
-        info!("zfs {}", args.join(" "));
-        if !self.pretend {
-            let output = self.cmd().args(args).output()?;
-            debug!("{:?}", output);
+        info!("zfs destroy {}", fs.name);
+        match self.pretend {
+            true => Ok(()),
+            false => self.backend.destroy(&fs.name),
         }
-        Ok(())
     }
 }
 
@@ -217,29 +667,97 @@ fn eval(opt: Option<&&str>) -> bool {
     }
 }
 
-fn str2fs(str: &str, fs_type: FsType) -> FS {
+/// Parses a line of `zfs list`. The columns are name, written, the general option, the
+/// label-specific options in the order of `labels`, the creation-time, the guid and used.
+fn str2fs<L: AsRef<str>>(str: &str, fs_type: FsType, labels: &[L]) -> FS {
     let p: Vec<&str> = str.split('\t').collect();
     let name = p[0].to_string();
     let date = DateTime::from_utc(
-        NaiveDateTime::from_timestamp(p[4].parse().unwrap_or_default(), 0),
+        NaiveDateTime::from_timestamp(
+            p.get(3 + labels.len())
+                .and_then(|c| c.parse().ok())
+                .unwrap_or_default(),
+            0,
+        ),
         Utc,
     );
     FS {
         name: name.clone(),
         written: p[1].parse().unwrap_or_default(),
-        snap: eval(p.get(2)) || eval(p.get(3)),
+        guid: p
+            .get(4 + labels.len())
+            .and_then(|c| c.parse().ok())
+            .unwrap_or_default(),
+        used: p
+            .get(5 + labels.len())
+            .and_then(|c| c.parse().ok())
+            .unwrap_or_default(),
+        snap: eval(p.get(2)),
+        labels: labels
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| eval(p.get(3 + i)))
+            .map(|(_, l)| l.as_ref().to_owned())
+            .collect(),
         date,
         fs_type,
         fs: match fs_type {
-            FsType::Filesystem => name,
+            FsType::Filesystem | FsType::Volume => name,
             FsType::Snapshot => name.split('@').next().unwrap().to_string(),
+            FsType::Bookmark => name.split('#').next().unwrap().to_string(),
         },
     }
 }
 
+/// Errors of zfs-commands, the typed variants carry the message of zfs.
+#[derive(Debug)]
 pub enum ZfsError {
     IOError(std::io::Error),
     InternalError(String),
+    Busy(String),
+    Exists(String),
+    HasClones(String),
+    PermissionDenied(String),
+    NoSuchDataset(String),
+    Failed(String),
+}
+
+impl ZfsError {
+    /// Classifies the stderr of a failed zfs-command.
+    pub fn from_stderr(stderr: &str) -> Self {
+        let message = stderr
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let lower = message.to_lowercase();
+        if lower.contains("is busy") {
+            ZfsError::Busy(message)
+        } else if lower.contains("already exists") {
+            ZfsError::Exists(message)
+        } else if lower.contains("dependent clones") {
+            ZfsError::HasClones(message)
+        } else if lower.contains("permission denied") || lower.contains("not permitted") {
+            ZfsError::PermissionDenied(message)
+        } else if lower.contains("does not exist") || lower.contains("could not find any") {
+            ZfsError::NoSuchDataset(message)
+        } else {
+            ZfsError::Failed(message)
+        }
+    }
+}
+
+/// Turns a non-zero exit status of a zfs-command into an error.
+pub fn check(output: process::Output) -> Result<process::Output, ZfsError> {
+    debug!("{:?}", output);
+    match output.status.success() {
+        true => Ok(output),
+        false if output.stderr.is_empty() => Err(ZfsError::Failed(output.status.to_string())),
+        false => Err(ZfsError::from_stderr(&String::from_utf8_lossy(
+            &output.stderr,
+        ))),
+    }
 }
 
 impl From<std::io::Error> for ZfsError {
@@ -253,37 +771,89 @@ impl fmt::Display for ZfsError {
         match self {
             ZfsError::IOError(e) => write!(f, "{}", e),
             ZfsError::InternalError(s) => write!(f, "{}", s),
+            ZfsError::Busy(s)
+            | ZfsError::Exists(s)
+            | ZfsError::HasClones(s)
+            | ZfsError::PermissionDenied(s)
+            | ZfsError::NoSuchDataset(s)
+            | ZfsError::Failed(s) => write!(f, "zfs: {}", s),
         }
     }
 }
 
+#[cfg(test)]
 mod should {
     use super::*;
+    use chrono::TimeZone;
+
+    const LABELS: [&str; 1] = ["weekly"];
 
     #[test]
     fn parse_zfs_output() {
-        let fs = str2fs("tank\t24576\t-\t-\t1608216521", FsType::Filesystem);
+        let fs = str2fs("tank\t24576\t-\t-\t1608216521", FsType::Filesystem, &LABELS);
         assert_eq!(fs.name, String::from("tank"));
-        assert_eq!(fs.written, 24576usize);
+        assert_eq!(fs.written, 24576);
         assert!(!fs.snap);
-        let fs = str2fs("tank\t24576\t-\ttrue\t1608216521", FsType::Filesystem);
-        assert!(fs.snap);
+        let fs = str2fs(
+            "tank\t24576\t-\ttrue\t1608216521",
+            FsType::Filesystem,
+            &LABELS,
+        );
+        assert!(!fs.snap);
+        assert!(fs.snap_for("weekly"));
         assert_eq!(
             fs.date,
             DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(1608216521, 0), Utc)
         );
-        let fs = str2fs("tank\t24576\ttrue\tfalse\t1608216521", FsType::Filesystem);
+        let fs = str2fs(
+            "tank\t24576\ttrue\tfalse\t1608216521",
+            FsType::Filesystem,
+            &LABELS,
+        );
         assert!(fs.snap);
-        let fs = str2fs("tank\t24576\ttrue\ttrue\t1608216521", FsType::Filesystem);
+        let fs = str2fs(
+            "tank\t24576\ttrue\ttrue\t1608216521",
+            FsType::Filesystem,
+            &LABELS,
+        );
         assert!(fs.snap);
     }
 
+    #[test]
+    fn parse_zfs_output_of_several_labels() {
+        let labels = ["hourly", "daily", "weekly"];
+        let fs = str2fs(
+            "tank\t24576\tfalse\t-\ttrue\tfalse\t1608216521\t8741652232914223614\t4096",
+            FsType::Filesystem,
+            &labels,
+        );
+        assert!(!fs.snap_for("hourly"));
+        assert!(fs.snap_for("daily"));
+        assert!(!fs.snap_for("weekly"));
+        assert!(fs.managed());
+        assert_eq!(
+            fs.date,
+            DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(1608216521, 0), Utc)
+        );
+        assert_eq!(fs.guid(), 8741652232914223614);
+        assert_eq!(fs.used(), 4096);
+    }
+
     #[test]
     fn find_expendable_snapshots_enough() {
-        let zfs = Zfs::new(true, "zfs-snapshot", "weekly", 1usize, "2019-12-30_1807");
+        let zfs = Zfs::new(
+            true,
+            "zfs-snapshot",
+            vec!["weekly".into()],
+            Utc.ymd(2019, 12, 30).and_hms(18, 7, 0),
+        );
         let fs_snaps = get_snaps();
-        let fs_orig = str2fs("tank/SRV/www\t245643\t-\t-\t121212112", FsType::Filesystem);
-        let expendables = zfs.find_expendable_snapshots(&fs_orig, &fs_snaps);
+        let fs_orig = str2fs(
+            "tank/SRV/www\t245643\t-\t-\t121212112",
+            FsType::Filesystem,
+            &LABELS,
+        );
+        let expendables = zfs.find_expendable_snapshots("weekly", |_| keep(1), &fs_orig, &fs_snaps);
 
         assert_eq!(expendables.len(), 2);
         assert_eq!(
@@ -298,28 +868,134 @@ mod should {
 
     #[test]
     fn find_expendable_snapshots_to_less() {
-        let zfs = Zfs::new(true, "zfs-snapshot", "weekly", 4usize, "2019-12-30_1807");
+        let zfs = Zfs::new(
+            true,
+            "zfs-snapshot",
+            vec!["weekly".into()],
+            Utc.ymd(2019, 12, 30).and_hms(18, 7, 0),
+        );
         let fs_snaps = get_snaps();
-        let fs_orig = str2fs("tank/SRV/www\t245643\t-\t-\t121212112", FsType::Filesystem);
-        let expendables = zfs.find_expendable_snapshots(&fs_orig, &fs_snaps);
+        let fs_orig = str2fs(
+            "tank/SRV/www\t245643\t-\t-\t121212112",
+            FsType::Filesystem,
+            &LABELS,
+        );
+        let expendables = zfs.find_expendable_snapshots("weekly", |_| keep(4), &fs_orig, &fs_snaps);
 
         assert!(expendables.is_empty());
     }
 
-    #[allow(dead_code)]
+    #[test]
+    fn order_snapshots_of_the_same_second_by_suffix() {
+        let zfs = Zfs::new(true, "zfs-snappers", vec!["weekly".into()], Utc::now());
+        let fs = str2fs("tank\t0\ttrue\t-\t0", FsType::Filesystem, &LABELS);
+        let snaps: Vec<FS> = ["1207.10", "1207.2", "1207"]
+            .iter()
+            .map(|time| {
+                let line =
+                    format!("tank@zfs-snappers_weekly-2019-12-30-{time}\t0\t-\t-\t1577707620");
+                str2fs(&line, FsType::Snapshot, &LABELS)
+            })
+            .collect();
+        let names: Vec<&str> = zfs
+            .filter_snaps(Some("weekly"), &fs, &snaps)
+            .iter()
+            .map(|sn| sn.short_name())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "zfs-snappers_weekly-2019-12-30-1207",
+                "zfs-snappers_weekly-2019-12-30-1207.2",
+                "zfs-snappers_weekly-2019-12-30-1207.10"
+            ]
+        );
+    }
+
+    #[test]
+    fn last_snapshot_of_the_most_behind_filesystem() {
+        let zfs = Zfs::new(true, "zfs-snappers", vec!["weekly".into()], Utc::now());
+        let fs = |name: &str| {
+            str2fs(
+                &format!("{name}\t0\ttrue\t-\t0"),
+                FsType::Filesystem,
+                &LABELS,
+            )
+        };
+        let sn = |name: &str, creation: i64| {
+            let line = format!("{name}\t0\t-\t-\t{creation}");
+            str2fs(&line, FsType::Snapshot, &LABELS)
+        };
+        let snaps = [
+            sn("tank/a@zfs-snappers_weekly-2019-12-16-1207", 1576498020),
+            sn("tank/a@zfs-snappers_weekly-2019-12-30-1207", 1577707620),
+            sn("tank/b@zfs-snappers_weekly-2019-12-23-1207", 1577102820),
+        ];
+        let filesystems = [fs("tank/a"), fs("tank/b")];
+        assert_eq!(
+            zfs.last_snapshot("weekly", &filesystems, &snaps),
+            Some(Utc.ymd(2019, 12, 23).and_hms(12, 7, 0))
+        );
+        let filesystems = [fs("tank/a"), fs("tank/b"), fs("tank/c")];
+        assert_eq!(zfs.last_snapshot("weekly", &filesystems, &snaps), None);
+    }
+
+    #[test]
+    fn classify_zfs_errors() {
+        let busy = "cannot destroy snapshot tank/www@x: dataset is busy\n";
+        assert!(matches!(ZfsError::from_stderr(busy), ZfsError::Busy(_)));
+        let clones = "cannot destroy 'tank/www@x': snapshot has dependent clones\n\
+                      use '-R' to destroy the following datasets:\ntank/clone\n";
+        assert!(matches!(
+            ZfsError::from_stderr(clones),
+            ZfsError::HasClones(_)
+        ));
+        let exists = "cannot create snapshot 'tank/www@x': dataset already exists\n";
+        assert!(matches!(ZfsError::from_stderr(exists), ZfsError::Exists(_)));
+        let denied = "cannot create snapshots : permission denied\n";
+        assert!(matches!(
+            ZfsError::from_stderr(denied),
+            ZfsError::PermissionDenied(_)
+        ));
+        let missing = "cannot open 'tank/nope': dataset does not exist\n";
+        assert!(matches!(
+            ZfsError::from_stderr(missing),
+            ZfsError::NoSuchDataset(_)
+        ));
+        let other = "internal error: out of memory\n";
+        assert_eq!(
+            ZfsError::from_stderr(other).to_string(),
+            "zfs: internal error: out of memory"
+        );
+    }
+
+    fn keep(keep: usize) -> Policy {
+        Policy {
+            keep,
+            min_size: Size(0),
+            keep_within: None,
+            max_age: None,
+            gfs: None,
+            zone: Zone::Utc,
+        }
+    }
+
     fn get_snaps() -> Vec<FS> {
         vec![
             str2fs(
                 "tank/SRV/www@zfs-snapshot_weekly-2019-12-30_1207\t23234\t-\t-\t1608216421",
                 FsType::Snapshot,
+                &LABELS,
             ),
             str2fs(
                 "tank/SRV/www@zfs-snapshot_weekly-2019-12-30_1907\t245643\t-\t-\t1608216921",
                 FsType::Snapshot,
+                &LABELS,
             ),
             str2fs(
                 "tank/SRV/www@zfs-snapshot_weekly-2019-12-30_1607\t12340\t-\t-\t1608216821",
                 FsType::Snapshot,
+                &LABELS,
             ),
         ]
     }
@@ -0,0 +1,118 @@
+//! zone
+//! The time zone of snapshot-names, calendar buckets and schedules.
+use chrono::{DateTime, FixedOffset, Local, Offset, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+/// A time zone given as 'UTC', 'local' or a name like 'Europe/Berlin'.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Zone {
+    #[default]
+    Utc,
+    /// The zone of the system, if its name is unknown.
+    Local,
+    Named(Tz),
+}
+
+/// Name of the zone of the system, from `TZ` or the link `/etc/localtime`.
+fn local_name() -> Option<Tz> {
+    let name = match env::var("TZ") {
+        Ok(tz) => tz.trim_start_matches(':').to_owned(),
+        Err(_) => {
+            let link = fs::read_link("/etc/localtime").ok()?;
+            let link = link.to_string_lossy();
+            link.split_once("zoneinfo/")?.1.to_owned()
+        }
+    };
+    name.parse().ok()
+}
+
+impl FromStr for Zone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "UTC" | "utc" => Ok(Zone::Utc),
+            "local" => Ok(local_name().map_or(Zone::Local, Zone::Named)),
+            name => name.parse().map(Zone::Named).map_err(|_| {
+                format!("unknown time zone '{s}', use 'UTC', 'local' or e.g. 'Europe/Berlin'")
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for Zone {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Zone::Utc => write!(f, "UTC"),
+            Zone::Local => write!(f, "local"),
+            Zone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+impl Zone {
+    /// `time` in this zone.
+    pub fn at(&self, time: &DateTime<Utc>) -> DateTime<FixedOffset> {
+        let offset = match self {
+            Zone::Utc => FixedOffset::east(0),
+            Zone::Local => time.with_timezone(&Local).offset().fix(),
+            Zone::Named(tz) => time.with_timezone(tz).offset().fix(),
+        };
+        time.with_timezone(&offset)
+    }
+
+    /// Marks names with the zone at `time`, telling apart the same local time before and after
+    /// a change of daylight saving. The abbreviation of the zone like 'CET' or 'CEST', or the
+    /// offset like 'p0530' or 'm0300' if it has none.
+    pub fn marker(&self, time: &DateTime<Utc>) -> String {
+        let abbreviation = match self {
+            Zone::Utc => String::from("UTC"),
+            Zone::Local => String::new(),
+            Zone::Named(tz) => time.with_timezone(tz).format("%Z").to_string(),
+        };
+        if !abbreviation.is_empty() && abbreviation.chars().all(|c| c.is_ascii_alphabetic()) {
+            return abbreviation;
+        }
+        let offset = self.at(time).offset().local_minus_utc();
+        let sign = if offset < 0 { 'm' } else { 'p' };
+        let minutes = offset.abs() / 60;
+        format!("{sign}{:02}{:02}", minutes / 60, minutes % 60)
+    }
+}
+
+#[cfg(test)]
+mod should {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn mark_names_by_zone() {
+        let winter = Utc.ymd(2022, 1, 12).and_hms(12, 0, 0);
+        let summer = Utc.ymd(2022, 7, 12).and_hms(12, 0, 0);
+        let berlin: Zone = "Europe/Berlin".parse().unwrap();
+        assert_eq!(berlin.marker(&winter), "CET");
+        assert_eq!(berlin.marker(&summer), "CEST");
+        assert_eq!(berlin.at(&summer).format("%H:%M").to_string(), "14:00");
+        assert_eq!(Zone::Utc.marker(&summer), "UTC");
+        let kolkata: Zone = "Asia/Kolkata".parse().unwrap();
+        assert_eq!(kolkata.marker(&summer), "IST");
+        let sao_paulo: Zone = "America/Sao_Paulo".parse().unwrap();
+        assert_eq!(sao_paulo.marker(&summer), "m0300");
+        assert!("Mars/Olympus_Mons".parse::<Zone>().is_err());
+    }
+}